@@ -0,0 +1,289 @@
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{initial_window, CongestionControl, RateSample};
+
+const HIGH_GAIN: f64 = 2.885; // 2/ln(2)
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const CWND_GAIN: f64 = 2.0;
+const BTL_BW_FILTER_ROUNDS: u64 = 10;
+const MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+const MIN_PIPE_CWND_SEGMENTS: usize = 4;
+const FULL_BW_THRESH: f64 = 1.25;
+const FULL_BW_COUNT: u32 = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+	Startup,
+	Drain,
+	ProbeBw,
+	ProbeRtt,
+}
+
+// ボトルネック帯域と最小RTTのモデルに基づく輻輳制御 (BBR v1)
+pub struct Bbr {
+	mss: usize,
+	mode: Mode,
+	cwnd: usize,
+	prior_cwnd: usize,
+	pacing_gain: f64,
+	cwnd_gain: f64,
+	btl_bw_samples: VecDeque<(u64, f64)>, //(ラウンド, 配送レート)
+	min_rtt: Option<Duration>,
+	min_rtt_stamp: Instant,
+	round_count: u64,
+	next_round_delivered: u64,
+	full_bw: f64,
+	full_bw_count: u32,
+	filled_pipe: bool,
+	cycle_index: usize,
+	cycle_stamp: Instant,
+	probe_rtt_done_stamp: Option<Instant>,
+}
+
+impl Bbr {
+	pub fn new() -> Self {
+		let now = Instant::now();
+		Bbr {
+			mss: 0,
+			mode: Mode::Startup,
+			cwnd: 0,
+			prior_cwnd: 0,
+			pacing_gain: HIGH_GAIN,
+			cwnd_gain: HIGH_GAIN,
+			btl_bw_samples: VecDeque::new(),
+			min_rtt: None,
+			min_rtt_stamp: now,
+			round_count: 0,
+			next_round_delivered: 0,
+			full_bw: 0.0,
+			full_bw_count: 0,
+			filled_pipe: false,
+			cycle_index: 0,
+			cycle_stamp: now,
+			probe_rtt_done_stamp: None,
+		}
+	}
+
+	// 直近10ラウンドの配送レートの最大値
+	fn btl_bw(&self) -> f64 {
+		self.btl_bw_samples.iter().map(|&(_, rate)| rate).fold(0.0, f64::max)
+	}
+
+	fn bdp(&self, gain: f64) -> usize {
+		match self.min_rtt {
+			Some(min_rtt) if self.btl_bw() > 0.0 => {
+				let min_rtt = min_rtt.as_secs() as f64 + min_rtt.subsec_micros() as f64 / 1_000_000.0;
+				(gain * self.btl_bw() * min_rtt) as usize
+			}
+			_ => initial_window(self.mss),
+		}
+	}
+
+	fn min_cwnd(&self) -> usize {
+		MIN_PIPE_CWND_SEGMENTS * self.mss
+	}
+
+	fn update_btl_bw(&mut self, sample: &RateSample) {
+		if sample.prior_delivered >= self.next_round_delivered {
+			self.next_round_delivered = sample.total_delivered;
+			self.round_count += 1;
+			self.check_full_pipe(sample);
+		}
+		if !sample.is_app_limited || sample.delivery_rate >= self.btl_bw() {
+			self.btl_bw_samples.push_back((self.round_count, sample.delivery_rate));
+		}
+		while let Some(&(round, _)) = self.btl_bw_samples.front() {
+			if self.round_count - round < BTL_BW_FILTER_ROUNDS {
+				break;
+			}
+			self.btl_bw_samples.pop_front();
+		}
+	}
+
+	// 3ラウンド続けて帯域が25%以上伸びなければパイプが埋まったとみなす
+	fn check_full_pipe(&mut self, sample: &RateSample) {
+		if self.filled_pipe || sample.is_app_limited {
+			return;
+		}
+		let btl_bw = self.btl_bw();
+		if btl_bw >= self.full_bw * FULL_BW_THRESH {
+			self.full_bw = btl_bw;
+			self.full_bw_count = 0;
+			return;
+		}
+		self.full_bw_count += 1;
+		if self.full_bw_count >= FULL_BW_COUNT {
+			debug!("bbr: pipe filled, btl_bw {:.0} B/s", btl_bw);
+			self.filled_pipe = true;
+		}
+	}
+
+	fn update_min_rtt(&mut self, sample: &RateSample, now: Instant) {
+		let expired = now - self.min_rtt_stamp > MIN_RTT_FILTER_LEN;
+		if self.min_rtt.is_none_or(|min_rtt| sample.rtt <= min_rtt) || expired {
+			self.min_rtt = Some(sample.rtt);
+			self.min_rtt_stamp = now;
+		}
+		if expired && self.mode != Mode::ProbeRtt {
+			self.enter_mode(Mode::ProbeRtt, now);
+			self.prior_cwnd = self.cwnd;
+			self.probe_rtt_done_stamp = None;
+		}
+	}
+
+	fn enter_mode(&mut self, mode: Mode, now: Instant) {
+		debug!("bbr: {:?} -> {:?}", self.mode, mode);
+		self.mode = mode;
+		match mode {
+			Mode::Startup => {
+				self.pacing_gain = HIGH_GAIN;
+				self.cwnd_gain = HIGH_GAIN;
+			}
+			Mode::Drain => {
+				self.pacing_gain = 1.0 / HIGH_GAIN;
+				self.cwnd_gain = HIGH_GAIN;
+			}
+			Mode::ProbeBw => {
+				// 帯域を減らす位相(1)以外からランダムに始める
+				self.cycle_index = (rand::random::<usize>() % (PACING_GAIN_CYCLE.len() - 1) + 2) % PACING_GAIN_CYCLE.len();
+				self.cycle_stamp = now;
+				self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+				self.cwnd_gain = CWND_GAIN;
+			}
+			Mode::ProbeRtt => {
+				self.pacing_gain = 1.0;
+				self.cwnd_gain = 1.0;
+			}
+		}
+	}
+
+	fn update_mode(&mut self, in_flight: usize, now: Instant) {
+		match self.mode {
+			Mode::Startup if self.filled_pipe => self.enter_mode(Mode::Drain, now),
+			Mode::Drain if in_flight <= self.bdp(1.0) => self.enter_mode(Mode::ProbeBw, now),
+			Mode::ProbeBw => self.advance_cycle(in_flight, now),
+			Mode::ProbeRtt => self.handle_probe_rtt(in_flight, now),
+			_ => {}
+		}
+	}
+
+	fn advance_cycle(&mut self, in_flight: usize, now: Instant) {
+		let min_rtt = self.min_rtt.unwrap_or_default();
+		let mut is_next_phase = now - self.cycle_stamp > min_rtt;
+		if self.pacing_gain > 1.0 {
+			is_next_phase = is_next_phase && in_flight >= self.bdp(self.pacing_gain);
+		} else if self.pacing_gain < 1.0 {
+			is_next_phase = is_next_phase || in_flight <= self.bdp(1.0);
+		}
+		if is_next_phase {
+			self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+			self.cycle_stamp = now;
+			self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+		}
+	}
+
+	// ウィンドウを4セグメントに絞り、200ms以上保ってから元に戻る
+	fn handle_probe_rtt(&mut self, in_flight: usize, now: Instant) {
+		match self.probe_rtt_done_stamp {
+			None if in_flight <= self.min_cwnd() => {
+				self.probe_rtt_done_stamp = Some(now + PROBE_RTT_DURATION);
+			}
+			Some(done) if now > done => {
+				self.min_rtt_stamp = now;
+				self.cwnd = max(self.cwnd, self.prior_cwnd);
+				let mode = if self.filled_pipe { Mode::ProbeBw } else { Mode::Startup };
+				self.enter_mode(mode, now);
+			}
+			_ => {}
+		}
+	}
+
+	fn update_cwnd(&mut self, sample: &RateSample) {
+		let target = self.bdp(self.cwnd_gain) + 3 * self.mss;
+		let grown = self.cwnd + sample.delivered as usize;
+		if self.filled_pipe {
+			self.cwnd = min(grown, target);
+		} else if self.cwnd < target || sample.total_delivered < initial_window(self.mss) as u64 {
+			self.cwnd = grown;
+		}
+		self.cwnd = max(self.cwnd, self.min_cwnd());
+		if self.mode == Mode::ProbeRtt {
+			self.cwnd = min(self.cwnd, self.min_cwnd());
+		}
+	}
+}
+
+impl Default for Bbr {
+	fn default() -> Self {
+		Bbr::new()
+	}
+}
+
+impl CongestionControl for Bbr {
+	fn name(&self) -> &'static str {
+		"bbr"
+	}
+
+	fn init(&mut self, mss: usize) {
+		self.mss = mss;
+		self.cwnd = initial_window(mss);
+	}
+
+	fn cwnd(&self) -> usize {
+		self.cwnd
+	}
+
+	fn ssthresh(&self) -> usize {
+		usize::MAX
+	}
+
+	// ウィンドウは配送レートの標本から計算する
+	fn on_ack(&mut self, _acked: usize, _in_flight: usize, _rtt: Option<Duration>) {}
+
+	fn on_rate_sample(&mut self, sample: &RateSample, in_flight: usize) {
+		let now = Instant::now();
+		self.update_btl_bw(sample);
+		self.update_min_rtt(sample, now);
+		self.update_mode(in_flight, now);
+		self.update_cwnd(sample);
+	}
+
+	// ロス回復中はフライト中のバイト数を保つ
+	fn on_congestion_event(&mut self, in_flight: usize) {
+		self.prior_cwnd = self.cwnd;
+		self.cwnd = max(in_flight, self.min_cwnd());
+	}
+
+	fn on_recovery_exit(&mut self) {
+		self.cwnd = max(self.cwnd, self.prior_cwnd);
+	}
+
+	fn on_timeout(&mut self, _in_flight: usize) {
+		self.prior_cwnd = self.cwnd;
+		self.cwnd = self.mss;
+	}
+
+	fn pacing_rate(&self) -> Option<f64> {
+		let btl_bw = self.btl_bw();
+		if btl_bw > 0.0 {
+			Some(self.pacing_gain * btl_bw)
+		} else {
+			None
+		}
+	}
+
+	fn state(&self) -> String {
+		format!(
+			"mode {:?}, cwnd {}, btl_bw {:.0} B/s, min_rtt {:?}, pacing_gain {:.2}, round {}",
+			self.mode,
+			self.cwnd,
+			self.btl_bw(),
+			self.min_rtt,
+			self.pacing_gain,
+			self.round_count
+		)
+	}
+}
@@ -0,0 +1,233 @@
+use std::cmp::max;
+use std::time::{Duration, Instant};
+
+use super::{initial_window, CongestionControl};
+
+const C: f64 = 0.4;
+const BETA_CUBIC: f64 = 0.7;
+
+// RFC 8312 の CUBIC。計算はセグメント単位で行う
+pub struct Cubic {
+	mss: usize,
+	cwnd: f64,
+	ssthresh: usize,
+	w_max: f64,
+	w_last_max: f64,
+	w_est: f64,
+	k: f64,
+	origin_point: f64,
+	epoch_start: Option<Instant>,
+}
+
+impl Cubic {
+	pub fn new() -> Self {
+		Cubic {
+			mss: 0,
+			cwnd: 0.0,
+			ssthresh: usize::MAX,
+			w_max: 0.0,
+			w_last_max: 0.0,
+			w_est: 0.0,
+			k: 0.0,
+			origin_point: 0.0,
+			epoch_start: None,
+		}
+	}
+
+	// 高速収束を含むW_maxの更新とウィンドウの縮小 (RFC 8312 4.5, 4.6)
+	fn reduce(&mut self) {
+		self.epoch_start = None;
+		if self.cwnd < self.w_last_max {
+			self.w_last_max = self.cwnd;
+			self.w_max = self.cwnd * (1.0 + BETA_CUBIC) / 2.0;
+		} else {
+			self.w_max = self.cwnd;
+			self.w_last_max = self.cwnd;
+		}
+		self.ssthresh = max((self.cwnd * BETA_CUBIC * self.mss as f64) as usize, 2 * self.mss);
+	}
+}
+
+impl Default for Cubic {
+	fn default() -> Self {
+		Cubic::new()
+	}
+}
+
+impl CongestionControl for Cubic {
+	fn name(&self) -> &'static str {
+		"cubic"
+	}
+
+	fn init(&mut self, mss: usize) {
+		self.mss = mss;
+		self.cwnd = (initial_window(mss) / mss) as f64;
+	}
+
+	fn cwnd(&self) -> usize {
+		(self.cwnd * self.mss as f64) as usize
+	}
+
+	fn ssthresh(&self) -> usize {
+		self.ssthresh
+	}
+
+	fn on_ack(&mut self, acked: usize, _in_flight: usize, rtt: Option<Duration>) {
+		let acked = acked as f64 / self.mss as f64;
+		if self.cwnd() < self.ssthresh {
+			self.cwnd += acked.min(1.0);
+			return;
+		}
+
+		let now = Instant::now();
+		let epoch_start = match self.epoch_start {
+			Some(epoch_start) => epoch_start,
+			None => {
+				if self.cwnd < self.w_max {
+					self.k = ((self.w_max - self.cwnd) / C).cbrt();
+					self.origin_point = self.w_max;
+				} else {
+					self.k = 0.0;
+					self.origin_point = self.cwnd;
+				}
+				self.w_est = self.cwnd;
+				self.epoch_start = Some(now);
+				now
+			}
+		};
+
+		let rtt = rtt.map_or(0.0, |rtt| rtt.as_secs() as f64 + rtt.subsec_micros() as f64 / 1_000_000.0);
+		let elapsed = now - epoch_start;
+		let t = elapsed.as_secs() as f64 + elapsed.subsec_micros() as f64 / 1_000_000.0;
+		// RTT後のウィンドウを目標にする (RFC 8312 4.1)
+		let target = self.origin_point + C * (t + rtt - self.k).powi(3);
+
+		// TCP-friendly 領域: W_estは現在のW_cubic(t)と比べる (RFC 8312 4.2)
+		self.w_est += 3.0 * (1.0 - BETA_CUBIC) / (1.0 + BETA_CUBIC) * acked / self.cwnd;
+		let w_cubic = self.origin_point + C * (t - self.k).powi(3);
+		if self.w_est > w_cubic {
+			self.cwnd = self.cwnd.max(self.w_est);
+		} else if target > self.cwnd {
+			// concave / convex 領域 (RFC 8312 4.3, 4.4)
+			self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+		}
+	}
+
+	fn on_congestion_event(&mut self, _in_flight: usize) {
+		self.reduce();
+		self.cwnd = (self.cwnd * BETA_CUBIC).max(2.0);
+	}
+
+	fn on_timeout(&mut self, _in_flight: usize) {
+		self.reduce();
+		self.cwnd = 1.0;
+	}
+
+	fn state(&self) -> String {
+		let epoch = match self.epoch_start {
+			Some(epoch_start) => format!("{:?} ago", epoch_start.elapsed()),
+			None => "none".to_string(),
+		};
+		format!(
+			"cwnd {}, ssthresh {}, W_max {:.1}, K {:.3}, epoch start {}",
+			self.cwnd(),
+			self.ssthresh,
+			self.w_max,
+			self.k,
+			epoch
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MSS: usize = 1000;
+	const RTT: Duration = Duration::from_millis(100);
+
+	// 輻輳回避中で、epoch_startからelapsed経ったCUBIC
+	fn avoiding(cwnd: f64, w_max: f64, w_est: f64, elapsed: Duration) -> Cubic {
+		let mut cubic = Cubic::new();
+		cubic.init(MSS);
+		cubic.cwnd = cwnd;
+		cubic.ssthresh = 2 * MSS;
+		cubic.w_max = w_max;
+		cubic.w_est = w_est;
+		cubic.k = (w_max - cwnd).max(0.0).cbrt() / C.cbrt();
+		cubic.origin_point = w_max.max(cwnd);
+		cubic.epoch_start = Some(Instant::now() - elapsed);
+		cubic
+	}
+
+	#[test]
+	fn slow_start_grows_by_acked_segments() {
+		let mut cubic = Cubic::new();
+		cubic.init(MSS);
+		let initial = cubic.cwnd();
+		cubic.on_ack(MSS, 0, Some(RTT));
+		assert_eq!(cubic.cwnd(), initial + MSS);
+		// 1つのACKで増やすのは1セグメントまで
+		cubic.on_ack(3 * MSS, 0, Some(RTT));
+		assert_eq!(cubic.cwnd(), initial + 2 * MSS);
+	}
+
+	#[test]
+	fn tcp_friendly_region_compares_with_current_w_cubic() {
+		// W_cubic(t)はまだcwndのままだが、W_cubic(t+RTT)はずっと大きい
+		let mut cubic = avoiding(10.0, 10.0, 10.5, Duration::from_secs(0));
+		cubic.on_ack(MSS, 0, Some(Duration::from_secs(10)));
+		assert!(cubic.cwnd >= 10.5);
+		assert!(cubic.cwnd < 11.0);
+	}
+
+	#[test]
+	fn concave_region_approaches_w_max() {
+		let mut cubic = avoiding(8.0, 10.0, 8.0, Duration::from_secs(1));
+		cubic.on_ack(MSS, 0, Some(RTT));
+		assert!(cubic.cwnd > 8.1);
+		assert!(cubic.cwnd < 10.0);
+	}
+
+	#[test]
+	fn convex_region_grows_beyond_w_max() {
+		let mut cubic = avoiding(12.0, 10.0, 12.0, Duration::from_secs(2));
+		cubic.on_ack(MSS, 0, Some(RTT));
+		assert!(cubic.cwnd > 12.1);
+	}
+
+	#[test]
+	fn reduce_without_fast_convergence() {
+		let mut cubic = Cubic::new();
+		cubic.init(MSS);
+		cubic.cwnd = 10.0;
+		cubic.w_last_max = 5.0;
+		cubic.on_congestion_event(0);
+		assert_eq!(cubic.w_max, 10.0);
+		assert_eq!(cubic.w_last_max, 10.0);
+		assert_eq!(cubic.cwnd(), 7 * MSS);
+		assert_eq!(cubic.ssthresh(), 7 * MSS);
+		assert!(cubic.epoch_start.is_none());
+	}
+
+	#[test]
+	fn reduce_with_fast_convergence() {
+		let mut cubic = Cubic::new();
+		cubic.init(MSS);
+		cubic.cwnd = 10.0;
+		cubic.w_last_max = 20.0;
+		cubic.on_congestion_event(0);
+		assert_eq!(cubic.w_max, 8.5);
+		assert_eq!(cubic.w_last_max, 10.0);
+	}
+
+	#[test]
+	fn timeout_restarts_from_one_segment() {
+		let mut cubic = Cubic::new();
+		cubic.init(MSS);
+		cubic.cwnd = 10.0;
+		cubic.on_timeout(0);
+		assert_eq!(cubic.cwnd(), MSS);
+		assert_eq!(cubic.ssthresh(), 7 * MSS);
+	}
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+mod bbr;
+mod cubic;
+mod newreno;
+mod rate;
+
+pub use self::bbr::Bbr;
+pub use self::cubic::Cubic;
+pub use self::newreno::NewReno;
+pub use self::rate::{DeliverySnapshot, RateSample, RateSampler};
+
+// 輻輳制御アルゴリズムの共通インターフェース。ウィンドウはバイト単位
+pub trait CongestionControl: Send + Sync {
+	fn name(&self) -> &'static str;
+	// MSSが決まった時点で初期ウィンドウを設定する
+	fn init(&mut self, mss: usize);
+	fn cwnd(&self) -> usize;
+	fn ssthresh(&self) -> usize;
+	// 回復中でないときに新しいデータがACKされた
+	fn on_ack(&mut self, acked: usize, in_flight: usize, rtt: Option<Duration>);
+	// ACKまたはSACKで配送レートの標本が得られた
+	fn on_rate_sample(&mut self, _sample: &RateSample, _in_flight: usize) {}
+	// 重複ACKやSACKでロスを検出し、回復を始める
+	fn on_congestion_event(&mut self, in_flight: usize);
+	// 回復中に送ったデータが全てACKされた
+	fn on_recovery_exit(&mut self) {}
+	// 再送タイムアウト
+	fn on_timeout(&mut self, in_flight: usize);
+	// ペーシングする場合の送信レート (バイト/秒)
+	fn pacing_rate(&self) -> Option<f64> {
+		None
+	}
+	// デバッグログ用の内部状態
+	fn state(&self) -> String {
+		format!("cwnd {}, ssthresh {}", self.cwnd(), self.ssthresh())
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum CongestionAlgorithm {
+	#[default]
+	NewReno,
+	Cubic,
+	Bbr,
+}
+
+impl CongestionAlgorithm {
+	pub fn build(self) -> Box<dyn CongestionControl> {
+		match self {
+			CongestionAlgorithm::NewReno => Box::new(NewReno::new()),
+			CongestionAlgorithm::Cubic => Box::new(Cubic::new()),
+			CongestionAlgorithm::Bbr => Box::new(Bbr::new()),
+		}
+	}
+}
+
+// RFC 5681 3.1 の初期ウィンドウ
+pub fn initial_window(mss: usize) -> usize {
+	if mss > 2190 {
+		2 * mss
+	} else if mss > 1095 {
+		3 * mss
+	} else {
+		4 * mss
+	}
+}
@@ -0,0 +1,136 @@
+use std::cmp::{max, min};
+use std::time::Duration;
+
+use super::{initial_window, CongestionControl};
+
+// RFC 5681 のスロースタートと輻輳回避
+pub struct NewReno {
+	mss: usize,
+	cwnd: usize,
+	ssthresh: usize,
+	bytes_acked: usize, //輻輳回避中にACKされたバイト数 (RFC 3465)
+}
+
+impl NewReno {
+	pub fn new() -> Self {
+		NewReno {
+			mss: 0,
+			cwnd: 0,
+			ssthresh: usize::MAX,
+			bytes_acked: 0,
+		}
+	}
+}
+
+impl Default for NewReno {
+	fn default() -> Self {
+		NewReno::new()
+	}
+}
+
+impl CongestionControl for NewReno {
+	fn name(&self) -> &'static str {
+		"newreno"
+	}
+
+	fn init(&mut self, mss: usize) {
+		self.mss = mss;
+		self.cwnd = initial_window(mss);
+	}
+
+	fn cwnd(&self) -> usize {
+		self.cwnd
+	}
+
+	fn ssthresh(&self) -> usize {
+		self.ssthresh
+	}
+
+	fn on_ack(&mut self, acked: usize, _in_flight: usize, _rtt: Option<Duration>) {
+		if self.cwnd < self.ssthresh {
+			self.cwnd += min(acked, self.mss);
+			return;
+		}
+		self.bytes_acked += acked;
+		if self.bytes_acked >= self.cwnd {
+			self.bytes_acked -= self.cwnd;
+			self.cwnd += self.mss;
+		}
+	}
+
+	fn on_congestion_event(&mut self, in_flight: usize) {
+		self.ssthresh = max(in_flight / 2, 2 * self.mss);
+		self.cwnd = self.ssthresh;
+		self.bytes_acked = 0;
+	}
+
+	fn on_timeout(&mut self, in_flight: usize) {
+		self.ssthresh = max(in_flight / 2, 2 * self.mss);
+		self.cwnd = self.mss;
+		self.bytes_acked = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MSS: usize = 1000;
+
+	fn newreno() -> NewReno {
+		let mut newreno = NewReno::new();
+		newreno.init(MSS);
+		newreno
+	}
+
+	#[test]
+	fn slow_start_grows_by_acked_bytes() {
+		let mut newreno = newreno();
+		assert_eq!(newreno.cwnd(), 4 * MSS);
+		newreno.on_ack(MSS / 2, 0, None);
+		assert_eq!(newreno.cwnd(), 4 * MSS + MSS / 2);
+		// 1つのACKで増やすのは1 SMSSまで (RFC 5681 3.1)
+		newreno.on_ack(3 * MSS, 0, None);
+		assert_eq!(newreno.cwnd(), 5 * MSS + MSS / 2);
+	}
+
+	#[test]
+	fn congestion_avoidance_counts_bytes() {
+		let mut newreno = newreno();
+		newreno.ssthresh = 4 * MSS;
+		for _ in 0..3 {
+			newreno.on_ack(MSS, 0, None);
+		}
+		assert_eq!(newreno.cwnd(), 4 * MSS);
+		// cwnd分のバイトがACKされたら1 SMSS増やす
+		newreno.on_ack(2 * MSS, 0, None);
+		assert_eq!(newreno.cwnd(), 5 * MSS);
+		assert_eq!(newreno.bytes_acked, MSS);
+	}
+
+	#[test]
+	fn congestion_event_halves_flight() {
+		let mut newreno = newreno();
+		newreno.on_congestion_event(10 * MSS);
+		assert_eq!(newreno.ssthresh(), 5 * MSS);
+		assert_eq!(newreno.cwnd(), 5 * MSS);
+	}
+
+	#[test]
+	fn congestion_event_ssthresh_floor() {
+		let mut newreno = newreno();
+		newreno.on_congestion_event(MSS);
+		assert_eq!(newreno.ssthresh(), 2 * MSS);
+		assert_eq!(newreno.cwnd(), 2 * MSS);
+	}
+
+	#[test]
+	fn timeout_restarts_from_one_segment() {
+		let mut newreno = newreno();
+		newreno.bytes_acked = MSS;
+		newreno.on_timeout(MSS);
+		assert_eq!(newreno.ssthresh(), 2 * MSS);
+		assert_eq!(newreno.cwnd(), MSS);
+		assert_eq!(newreno.bytes_acked, 0);
+	}
+}
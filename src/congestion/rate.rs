@@ -0,0 +1,105 @@
+use std::cmp::max;
+use std::time::{Duration, Instant};
+
+// 配送レートの推定 (draft-cheng-iccrg-delivery-rate-estimation)
+pub struct RateSampler {
+	delivered: u64, //これまでに配送されたバイト数
+	delivered_time: Instant,
+	first_sent_time: Instant,
+	app_limited: u64, //0でなければ、配送量がこの値を超えるまでアプリ制限
+	newest: Option<DeliverySnapshot>, //今回のACKで配送されたうち最後に送信したセグメント
+}
+
+// 送信時点の配送状況。再送キューの各セグメントが持つ
+#[derive(Clone, Copy)]
+pub struct DeliverySnapshot {
+	delivered: u64,
+	delivered_time: Instant,
+	first_sent_time: Instant,
+	sent_at: Instant,
+	is_app_limited: bool,
+}
+
+pub struct RateSample {
+	pub delivery_rate: f64, //バイト/秒
+	pub delivered: u64,     //区間中に配送されたバイト数
+	pub prior_delivered: u64,
+	pub total_delivered: u64,
+	pub rtt: Duration,
+	pub is_app_limited: bool,
+}
+
+impl RateSampler {
+	pub fn new() -> Self {
+		let now = Instant::now();
+		RateSampler {
+			delivered: 0,
+			delivered_time: now,
+			first_sent_time: now,
+			app_limited: 0,
+			newest: None,
+		}
+	}
+
+	pub fn on_send(&mut self, nothing_in_flight: bool, now: Instant) -> DeliverySnapshot {
+		if nothing_in_flight {
+			self.first_sent_time = now;
+			self.delivered_time = now;
+		}
+		DeliverySnapshot {
+			delivered: self.delivered,
+			delivered_time: self.delivered_time,
+			first_sent_time: self.first_sent_time,
+			sent_at: now,
+			is_app_limited: self.app_limited != 0,
+		}
+	}
+
+	// ACKまたはSACKでセグメントの配送を確認した
+	pub fn on_delivered(&mut self, snapshot: &DeliverySnapshot, len: usize, now: Instant) {
+		self.delivered += len as u64;
+		self.delivered_time = now;
+		if self.app_limited != 0 && self.delivered > self.app_limited {
+			self.app_limited = 0;
+		}
+		let is_newer = match self.newest {
+			Some(newest) => snapshot.delivered >= newest.delivered,
+			None => true,
+		};
+		if is_newer {
+			self.first_sent_time = snapshot.sent_at;
+			self.newest = Some(*snapshot);
+		}
+	}
+
+	// 送るデータが無くウィンドウを使い切れていない
+	pub fn on_app_limited(&mut self, in_flight: usize) {
+		self.app_limited = max(self.delivered + in_flight as u64, 1);
+	}
+
+	pub fn take_sample(&mut self) -> Option<RateSample> {
+		let snapshot = self.newest.take()?;
+		let send_elapsed = snapshot.sent_at - snapshot.first_sent_time;
+		let ack_elapsed = self.delivered_time - snapshot.delivered_time;
+		let interval = max(send_elapsed, ack_elapsed);
+		let interval = interval.as_secs() as f64 + interval.subsec_micros() as f64 / 1_000_000.0;
+		if interval <= 0.0 {
+			return None;
+		}
+		let delivered = self.delivered - snapshot.delivered;
+		Some(RateSample {
+			delivery_rate: delivered as f64 / interval,
+			delivered,
+			prior_delivered: snapshot.delivered,
+			total_delivered: self.delivered,
+			rtt: self.delivered_time - snapshot.sent_at,
+			is_app_limited: snapshot.is_app_limited,
+		})
+	}
+}
+
+impl Default for RateSampler {
+	fn default() -> Self {
+		RateSampler::new()
+	}
+}
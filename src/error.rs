@@ -0,0 +1,19 @@
+// failure_deriveが生成するimplは新しいrustcでnon_local_definitionsに引っかかる
+#![allow(non_local_definitions)]
+
+use failure::Fail;
+
+// 接続が使えなくなった理由。read/sendが返す
+#[derive(Copy, Clone, Debug, PartialEq, Fail)]
+pub enum TcpError {
+	#[fail(display = "connection timed out")]
+	TimedOut,
+	#[fail(display = "connection reset by peer")]
+	ConnectionReset,
+	#[fail(display = "connection refused")]
+	ConnectionRefused,
+	#[fail(display = "broken pipe")]
+	BrokenPipe,
+	#[fail(display = "transport endpoint is not connected")]
+	NotConnected,
+}
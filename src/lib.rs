@@ -1,5 +1,11 @@
 pub mod tcp;
 pub mod socket;
+pub mod congestion;
+pub mod error;
+mod option;
+mod reassembly;
+pub mod seq;
+pub mod timer;
 mod util;
 #[macro_use]
 extern crate log;
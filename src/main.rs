@@ -1,5 +1,6 @@
-use failure;
+use sheep_tcp::error::TcpError;
 use sheep_tcp::tcp::TCPManager;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::{process, thread};
 use std::{env, io, str};
@@ -34,23 +35,56 @@ fn main() {
 
 fn serve(tcp_manager: Arc<TCPManager>) -> Result<(), failure::Error> {
 	tcp_manager.listen(60000)?;
+	let active = Arc::new(AtomicUsize::new(0));
 	loop {
 		let stream_id = tcp_manager.accept();
 		let cloned_manager = tcp_manager.clone();
+		// 接続がどう終わっても数を戻す
+		active.fetch_add(1, Ordering::SeqCst);
+		let cloned_active = active.clone();
+		let registered = tcp_manager.set_close_callback(stream_id, move |error: Option<TcpError>| {
+			let remaining = cloned_active.fetch_sub(1, Ordering::SeqCst) - 1;
+			match error {
+				Some(e) => warn!("connection {:?} aborted: {}, active: {}", stream_id, e, remaining),
+				None => debug!("connection {:?} closed, active: {}", stream_id, remaining),
+			}
+		});
+		// acceptしてすぐに切れた
+		if let Err(e) = registered {
+			active.fetch_sub(1, Ordering::SeqCst);
+			error!("{}", e);
+			continue;
+		}
 		thread::spawn( move || {
 			loop {
 				let mut buffer = [0u8; 100];
 				let read_size = 10;
-				let nbytes = cloned_manager.read(stream_id, &mut buffer, read_size).unwrap();
+				let nbytes = match cloned_manager.read(stream_id, &mut buffer, read_size) {
+					Ok(nbytes) => nbytes,
+					Err(e) => {
+						error!("{}", e);
+						break;
+					}
+				};
 				if nbytes == 0 {
 					debug!("connection closed");
+					// 相手が閉じたのでこちらもFINを送る
+					if let Err(e) = cloned_manager.disconnect(stream_id) {
+						error!("{}", e);
+					}
 					break;
 				}
 				// reader.read_until(b'\n', &mut buffer)?;
 				print!("{}", str::from_utf8(&buffer[..nbytes]).unwrap());
 
-				cloned_manager.send(stream_id, &buffer[..nbytes]).unwrap();
+				// 相手がRSTを送ってきたら(ECONNRESET)、この接続は諦める
+				if let Err(e) = cloned_manager.send(stream_id, &buffer[..nbytes]) {
+					error!("{}", e);
+					break;
+				}
 			}
+			// 切れていた場合の理由はもう要らない
+			cloned_manager.release(stream_id);
 		});
 	}
 }
@@ -63,8 +97,9 @@ fn communicate(
 	let stream_id = tcp_manager.connect(addr, port)?;
 	let cloned = tcp_manager.clone();
 
+	// FINがACKされるまで待ってから終了する
 	ctrlc::set_handler(move || {
-		if let Err(e) = cloned.disconnect(stream_id) {
+		if let Err(e) = cloned.close(stream_id) {
 			error!("{}", e);
 		}
 		process::exit(0);
@@ -79,6 +114,10 @@ fn communicate(
 		let mut buffer = [0u8; 100];
 		let read_size = 10;
 		let nbytes = tcp_manager.read(stream_id, &mut buffer, read_size)?;
+		if nbytes == 0 {
+			info!("connection closed by peer");
+			return tcp_manager.close(stream_id);
+		}
 		// reader.read_until(b'\n', &mut buffer)?;
 		info!("{}", str::from_utf8(&buffer[..nbytes])?);
 	}
@@ -0,0 +1,237 @@
+use super::seq::SeqNum;
+
+// TCPオプションの解析と生成
+const KIND_EOL: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WSCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMPS: u8 = 8;
+
+const MSS_LEN: usize = 4;
+const WSCALE_LEN: usize = 3;
+const SACK_PERMITTED_LEN: usize = 2;
+const SACK_BLOCK_LEN: usize = 8;
+const TIMESTAMPS_LEN: usize = 10;
+pub const MAX_OPTIONS_LEN: usize = 40;
+pub const MAX_WSCALE: u8 = 14; // RFC 7323 2.3
+
+#[derive(Clone, Default, Debug)]
+pub struct TcpOptions {
+	pub mss: Option<u16>,
+	pub wscale: Option<u8>,
+	pub timestamps: Option<(u32, u32)>, // (TSval, TSecr)
+	pub sack_permitted: bool,
+	pub sack_blocks: Vec<(SeqNum, SeqNum)>, // (左端, 右端)
+}
+
+impl TcpOptions {
+	// 壊れたオプションを見つけたらそこで解析をやめる
+	pub fn parse(raw: &[u8]) -> Self {
+		let mut options = TcpOptions::default();
+		let mut i = 0;
+		while i < raw.len() {
+			let kind = raw[i];
+			if kind == KIND_EOL {
+				break;
+			}
+			if kind == KIND_NOP {
+				i += 1;
+				continue;
+			}
+			if i + 1 >= raw.len() {
+				break;
+			}
+			let len = raw[i + 1] as usize;
+			if len < 2 || i + len > raw.len() {
+				warn!("malformed tcp option: kind {}, len {}", kind, len);
+				break;
+			}
+			let data = &raw[i + 2..i + len];
+			match (kind, len) {
+				(KIND_MSS, MSS_LEN) => {
+					options.mss = Some(u16::from_be_bytes([data[0], data[1]]));
+				}
+				(KIND_WSCALE, WSCALE_LEN) => {
+					options.wscale = Some(data[0]);
+				}
+				(KIND_TIMESTAMPS, TIMESTAMPS_LEN) => {
+					let tsval = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+					let tsecr = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+					options.timestamps = Some((tsval, tsecr));
+				}
+				(KIND_SACK_PERMITTED, SACK_PERMITTED_LEN) => {
+					options.sack_permitted = true;
+				}
+				(KIND_SACK, _) if (len - 2).is_multiple_of(SACK_BLOCK_LEN) => {
+					for block in data.chunks(SACK_BLOCK_LEN) {
+						let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+						let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+						options.sack_blocks.push((SeqNum(left), SeqNum(right)));
+					}
+				}
+				_ => {
+					debug!("ignored tcp option: kind {}, len {}", kind, len);
+				}
+			}
+			i += len;
+		}
+		options
+	}
+
+	// 残りのオプション領域に入るSACKブロックの数
+	pub fn sack_blocks_capacity(&self) -> usize {
+		let used = self.to_bytes().len() + 4; // NOP 2つと種別、長さ
+		MAX_OPTIONS_LEN.saturating_sub(used) / SACK_BLOCK_LEN
+	}
+
+	// 4バイト境界までEOLで埋めたオプション列を返す
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		if let Some(mss) = self.mss {
+			bytes.push(KIND_MSS);
+			bytes.push(MSS_LEN as u8);
+			bytes.extend_from_slice(&mss.to_be_bytes());
+		}
+		if let Some(wscale) = self.wscale {
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_WSCALE);
+			bytes.push(WSCALE_LEN as u8);
+			bytes.push(wscale);
+		}
+		if let Some((tsval, tsecr)) = self.timestamps {
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_TIMESTAMPS);
+			bytes.push(TIMESTAMPS_LEN as u8);
+			bytes.extend_from_slice(&tsval.to_be_bytes());
+			bytes.extend_from_slice(&tsecr.to_be_bytes());
+		}
+		if self.sack_permitted {
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_SACK_PERMITTED);
+			bytes.push(SACK_PERMITTED_LEN as u8);
+		}
+		if !self.sack_blocks.is_empty() {
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_NOP);
+			bytes.push(KIND_SACK);
+			bytes.push((2 + self.sack_blocks.len() * SACK_BLOCK_LEN) as u8);
+			for (left, right) in self.sack_blocks.iter() {
+				bytes.extend_from_slice(&left.0.to_be_bytes());
+				bytes.extend_from_slice(&right.0.to_be_bytes());
+			}
+		}
+		while bytes.len() % 4 != 0 {
+			bytes.push(KIND_EOL);
+		}
+		bytes
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let options = TcpOptions {
+			mss: Some(1460),
+			wscale: Some(7),
+			timestamps: Some((1, u32::MAX)),
+			sack_permitted: true,
+			sack_blocks: vec![(SeqNum(u32::MAX - 1), SeqNum(10))],
+		};
+		let bytes = options.to_bytes();
+		assert!(bytes.len() <= MAX_OPTIONS_LEN);
+		assert_eq!(bytes.len() % 4, 0);
+		let parsed = TcpOptions::parse(&bytes);
+		assert_eq!(parsed.mss, Some(1460));
+		assert_eq!(parsed.wscale, Some(7));
+		assert_eq!(parsed.timestamps, Some((1, u32::MAX)));
+		assert!(parsed.sack_permitted);
+		assert_eq!(parsed.sack_blocks, options.sack_blocks);
+	}
+
+	#[test]
+	fn sack_blocks_fit_with_timestamps() {
+		let options = TcpOptions {
+			mss: None,
+			wscale: None,
+			timestamps: Some((1, 2)),
+			sack_permitted: false,
+			sack_blocks: Vec::new(),
+		};
+		assert_eq!(options.sack_blocks_capacity(), 3);
+	}
+
+	#[test]
+	fn empty() {
+		let parsed = TcpOptions::parse(&[]);
+		assert_eq!(parsed.mss, None);
+	}
+
+	#[test]
+	fn stops_at_eol() {
+		let parsed = TcpOptions::parse(&[KIND_EOL, KIND_MSS, 4, 0x05, 0xb4]);
+		assert_eq!(parsed.mss, None);
+	}
+
+	#[test]
+	fn skips_nop() {
+		let parsed = TcpOptions::parse(&[KIND_NOP, KIND_NOP, KIND_MSS, 4, 0x05, 0xb4]);
+		assert_eq!(parsed.mss, Some(1460));
+	}
+
+	#[test]
+	fn truncated_option() {
+		let parsed = TcpOptions::parse(&[KIND_NOP, KIND_MSS, 4, 0x05]);
+		assert_eq!(parsed.mss, None);
+	}
+
+	#[test]
+	fn kind_without_length() {
+		let parsed = TcpOptions::parse(&[KIND_NOP, KIND_NOP, KIND_MSS]);
+		assert_eq!(parsed.mss, None);
+	}
+
+	#[test]
+	fn length_too_short() {
+		for len in 0..2 {
+			let parsed = TcpOptions::parse(&[99, len, KIND_MSS, 4, 0x05, 0xb4]);
+			assert_eq!(parsed.mss, None);
+		}
+	}
+
+	#[test]
+	fn wrong_length_is_skipped() {
+		let parsed = TcpOptions::parse(&[KIND_MSS, 3, 0x05, KIND_MSS, 4, 0x05, 0xb4]);
+		assert_eq!(parsed.mss, Some(1460));
+	}
+
+	#[test]
+	fn wscale_with_wrong_length_is_skipped() {
+		let parsed = TcpOptions::parse(&[KIND_WSCALE, 4, 7, 0, KIND_NOP, KIND_WSCALE, 3, 14]);
+		assert_eq!(parsed.wscale, Some(14));
+	}
+
+	#[test]
+	fn truncated_timestamps() {
+		let parsed = TcpOptions::parse(&[KIND_NOP, KIND_NOP, KIND_TIMESTAMPS, 10, 0, 0, 0, 1, 0, 0]);
+		assert_eq!(parsed.timestamps, None);
+	}
+
+	#[test]
+	fn malformed_sack_is_ignored() {
+		let parsed = TcpOptions::parse(&[KIND_SACK, 6, 0, 0, 0, 1, KIND_NOP, KIND_NOP]);
+		assert!(parsed.sack_blocks.is_empty());
+	}
+
+	#[test]
+	fn unknown_option_is_skipped() {
+		let parsed = TcpOptions::parse(&[99, 3, 0, KIND_MSS, 4, 0x05, 0xb4]);
+		assert_eq!(parsed.mss, Some(1460));
+	}
+}
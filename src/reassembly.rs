@@ -0,0 +1,220 @@
+use std::cmp::{max, min};
+
+use super::seq::SeqNum;
+
+// 順序が入れ替わって届いたセグメントを保持する
+pub struct ReassemblyQueue {
+	// RCV.NXTからのオフセット順に並んだ、互いに重ならない区間
+	segments: Vec<Segment>,
+	// 最後に受け取ったデータの先頭。SACKブロックの順序に使う
+	last_received: Option<SeqNum>,
+	// 先に届いたFINのシーケンス番号。手前のデータが揃うまで処理を待つ
+	fin: Option<SeqNum>,
+}
+
+struct Segment {
+	seq: SeqNum,
+	data: Vec<u8>,
+}
+
+impl ReassemblyQueue {
+	pub fn new() -> Self {
+		ReassemblyQueue {
+			segments: Vec::new(),
+			last_received: None,
+			fin: None,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.segments.is_empty()
+	}
+
+	// 受信ウィンドウ内のデータを格納する。受信済みの部分とウィンドウ外は切り捨て、重なる区間は結合する
+	pub fn insert(&mut self, rcv_nxt: SeqNum, window: u32, seq: SeqNum, data: &[u8]) {
+		let mut data = data;
+		let mut start = seq - rcv_nxt;
+		if seq < rcv_nxt {
+			let duplicated = (rcv_nxt - seq) as usize;
+			if duplicated >= data.len() {
+				return;
+			}
+			data = &data[duplicated..];
+			start = 0;
+		}
+		if start >= window || data.is_empty() {
+			return;
+		}
+		let end = min(start + data.len() as u32, window);
+		data = &data[..(end - start) as usize];
+		self.last_received = Some(rcv_nxt + start);
+
+		// 隣接・重複する区間をまとめて1つにする
+		let first = self.segments.iter().position(|seg| seg.end(rcv_nxt) >= start);
+		let first = match first {
+			Some(first) => first,
+			None => {
+				self.segments.push(Segment { seq: rcv_nxt + start, data: data.to_vec() });
+				return;
+			}
+		};
+		let last = self.segments.iter().rposition(|seg| seg.start(rcv_nxt) <= end);
+		let last = match last {
+			Some(last) if last >= first => last,
+			_ => {
+				self.segments.insert(first, Segment { seq: rcv_nxt + start, data: data.to_vec() });
+				return;
+			}
+		};
+
+		let merged_start = min(start, self.segments[first].start(rcv_nxt));
+		let merged_end = max(end, self.segments[last].end(rcv_nxt));
+		let mut merged = vec![0u8; (merged_end - merged_start) as usize];
+		for seg in self.segments.drain(first..=last) {
+			let offset = (seg.start(rcv_nxt) - merged_start) as usize;
+			merged[offset..offset + seg.data.len()].copy_from_slice(&seg.data);
+		}
+		let offset = (start - merged_start) as usize;
+		merged[offset..offset + data.len()].copy_from_slice(data);
+		self.segments.insert(first, Segment { seq: rcv_nxt + merged_start, data: merged });
+	}
+
+	// 保持している区間をSACKブロックにする。最後に受け取ったデータを含む区間を先頭に置く (RFC 2018 4)
+	pub fn sack_blocks(&self, max_blocks: usize) -> Vec<(SeqNum, SeqNum)> {
+		let mut blocks: Vec<(SeqNum, SeqNum)> = self
+			.segments
+			.iter()
+			.map(|seg| (seg.seq, seg.seq + seg.data.len() as u32))
+			.collect();
+		if let Some(last) = self.last_received {
+			if let Some(i) = blocks.iter().position(|&(left, right)| last.is_between(left, right)) {
+				let block = blocks.remove(i);
+				blocks.insert(0, block);
+			}
+		}
+		blocks.truncate(max_blocks);
+		blocks
+	}
+
+	// FINを覚えておく。ウィンドウ外のFINは捨てる
+	pub fn insert_fin(&mut self, rcv_nxt: SeqNum, window: u32, fin_seq: SeqNum) {
+		if fin_seq - rcv_nxt <= window {
+			self.fin = Some(fin_seq);
+		}
+	}
+
+	// RCV.NXTがFINに追いついていれば取り出す
+	pub fn take_fin(&mut self, rcv_nxt: SeqNum) -> bool {
+		if self.fin == Some(rcv_nxt) {
+			self.fin = None;
+			true
+		} else {
+			false
+		}
+	}
+
+	// RCV.NXTから連続しているデータを取り出す
+	pub fn pop(&mut self, rcv_nxt: SeqNum) -> Option<Vec<u8>> {
+		if self.segments.first().is_some_and(|seg| seg.seq == rcv_nxt) {
+			Some(self.segments.remove(0).data)
+		} else {
+			None
+		}
+	}
+}
+
+impl Segment {
+	fn start(&self, rcv_nxt: SeqNum) -> u32 {
+		self.seq - rcv_nxt
+	}
+
+	fn end(&self, rcv_nxt: SeqNum) -> u32 {
+		self.start(rcv_nxt) + self.data.len() as u32
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::ReassemblyQueue;
+	use crate::seq::SeqNum;
+
+	const WINDOW: u32 = 1000;
+
+	fn rcv_nxt() -> SeqNum {
+		SeqNum(u32::MAX - 2)
+	}
+
+	#[test]
+	fn fills_hole_across_wrap() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 4, b"efgh");
+		assert!(queue.pop(rcv_nxt()).is_none());
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt(), b"abcd");
+		assert_eq!(queue.pop(rcv_nxt()), Some(b"abcdefgh".to_vec()));
+		assert!(queue.is_empty());
+	}
+
+	#[test]
+	fn merges_overlapping_segments() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 2, b"cdef");
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 8, b"ij");
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 4, b"efghi");
+		assert_eq!(queue.sack_blocks(4), vec![(rcv_nxt() + 2, rcv_nxt() + 10)]);
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt(), b"abc");
+		assert_eq!(queue.pop(rcv_nxt()), Some(b"abcdefghij".to_vec()));
+		assert!(queue.is_empty());
+	}
+
+	#[test]
+	fn trims_already_received_data() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() - 3, b"xyzab");
+		assert_eq!(queue.pop(rcv_nxt()), Some(b"ab".to_vec()));
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() - 3, b"xyz");
+		assert!(queue.is_empty());
+	}
+
+	#[test]
+	fn trims_data_beyond_window() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert(rcv_nxt(), 4, rcv_nxt() + 4, b"ef");
+		assert!(queue.is_empty());
+		queue.insert(rcv_nxt(), 4, rcv_nxt() + 2, b"cdef");
+		queue.insert(rcv_nxt(), 4, rcv_nxt(), b"ab");
+		assert_eq!(queue.pop(rcv_nxt()), Some(b"abcd".to_vec()));
+	}
+
+	#[test]
+	fn latest_block_comes_first() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 2, b"c");
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 6, b"g");
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 4, b"e");
+		assert_eq!(
+			queue.sack_blocks(2),
+			vec![(rcv_nxt() + 4, rcv_nxt() + 5), (rcv_nxt() + 2, rcv_nxt() + 3)]
+		);
+	}
+
+	#[test]
+	fn fin_waits_for_hole() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt() + 2, b"cd");
+		queue.insert_fin(rcv_nxt(), WINDOW, rcv_nxt() + 4);
+		assert!(!queue.take_fin(rcv_nxt()));
+		queue.insert(rcv_nxt(), WINDOW, rcv_nxt(), b"ab");
+		assert_eq!(queue.pop(rcv_nxt()), Some(b"abcd".to_vec()));
+		assert!(queue.take_fin(rcv_nxt() + 4));
+		assert!(!queue.take_fin(rcv_nxt() + 4));
+	}
+
+	#[test]
+	fn fin_beyond_window_is_dropped() {
+		let mut queue = ReassemblyQueue::new();
+		queue.insert_fin(rcv_nxt(), 4, rcv_nxt() + 5);
+		assert!(!queue.take_fin(rcv_nxt() + 5));
+		queue.insert_fin(rcv_nxt(), 0, rcv_nxt());
+		assert!(queue.take_fin(rcv_nxt()));
+	}
+}
@@ -0,0 +1,107 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Display};
+use std::ops::{Add, AddAssign, Sub};
+
+// 2^32 で一周するシーケンス番号 (RFC 1982 のシリアル番号算術で比較する)
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct SeqNum(pub u32);
+
+impl SeqNum {
+	// start <= self < end
+	pub fn is_between(self, start: SeqNum, end: SeqNum) -> bool {
+		self - start < end - start
+	}
+}
+
+impl Add<u32> for SeqNum {
+	type Output = SeqNum;
+	fn add(self, rhs: u32) -> SeqNum {
+		SeqNum(self.0.wrapping_add(rhs))
+	}
+}
+
+impl AddAssign<u32> for SeqNum {
+	fn add_assign(&mut self, rhs: u32) {
+		self.0 = self.0.wrapping_add(rhs);
+	}
+}
+
+impl Sub<u32> for SeqNum {
+	type Output = SeqNum;
+	fn sub(self, rhs: u32) -> SeqNum {
+		SeqNum(self.0.wrapping_sub(rhs))
+	}
+}
+
+// 2つのシーケンス番号の距離
+impl Sub<SeqNum> for SeqNum {
+	type Output = u32;
+	fn sub(self, rhs: SeqNum) -> u32 {
+		self.0.wrapping_sub(rhs.0)
+	}
+}
+
+impl PartialOrd for SeqNum {
+	fn partial_cmp(&self, other: &SeqNum) -> Option<Ordering> {
+		Some((self.0.wrapping_sub(other.0) as i32).cmp(&0))
+	}
+}
+
+impl Debug for SeqNum {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl Display for SeqNum {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::SeqNum;
+
+	#[test]
+	fn compare_across_wrap() {
+		let before_wrap = SeqNum(u32::MAX - 1);
+		let after_wrap = before_wrap + 3;
+		assert_eq!(after_wrap, SeqNum(1));
+		assert!(before_wrap < after_wrap);
+		assert!(after_wrap > before_wrap);
+		assert_eq!(after_wrap - before_wrap, 3);
+		assert_eq!(after_wrap - 3, before_wrap);
+	}
+
+	#[test]
+	fn compare_half_space() {
+		let seq = SeqNum(10);
+		assert!(seq + (1 << 31) - 1 > seq);
+		assert!(seq - (1 << 31) + 1 < seq);
+	}
+
+	#[test]
+	fn add_assign_wraps() {
+		let mut seq = SeqNum(u32::MAX);
+		seq += 1;
+		assert_eq!(seq, SeqNum(0));
+	}
+
+	#[test]
+	fn is_between_across_wrap() {
+		let start = SeqNum(u32::MAX - 1);
+		let end = start + 4;
+		assert!(start.is_between(start, end));
+		assert!(SeqNum(u32::MAX).is_between(start, end));
+		assert!(SeqNum(1).is_between(start, end));
+		assert!(!end.is_between(start, end));
+		assert!(!(start - 1).is_between(start, end));
+	}
+
+	#[test]
+	fn is_between_empty_range() {
+		let seq = SeqNum(100);
+		assert!(!seq.is_between(seq, seq));
+	}
+}
@@ -1,10 +1,28 @@
 use pnet::packet::tcp::{self, MutableTcpPacket};
+use pnet::packet::tcp::TcpFlags;
 use pnet::transport::TransportSender;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
 use std::fmt::{self, Debug};
 use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
 
-const TCP_SIZE: usize = 20;
-const TCP_INIT_WINDOW: usize = 1460;
+use super::congestion::{CongestionAlgorithm, CongestionControl, DeliverySnapshot, RateSampler};
+use super::error::TcpError;
+use super::option::{self, TcpOptions};
+use super::reassembly::ReassemblyQueue;
+use super::seq::SeqNum;
+use super::timer::{KeepaliveTimer, PersistTimer, RetransmissionTimer};
+
+pub const TCP_SIZE: usize = 20;
+pub const MSS: usize = 1460; //.envで指定が無い場合に広告するMSS
+const DEFAULT_MSS: usize = 536; // MSSオプションが無い場合 (RFC 1122 4.2.2.6)
+pub const MIN_MSS: usize = 88; // 相手が極端に小さいMSSを送ってきても、オプションを除いてデータを載せられるように (LinuxのTCP_MIN_MSSと同じ)
+const DUP_THRESH: usize = 3;
+const PACING_MAX_BURST: Duration = Duration::from_millis(10);
+
+// 接続がテーブルから消えるときに呼ばれる。正常に閉じたならNone
+pub type CloseCallback = Box<dyn FnOnce(Option<TcpError>) + Send + Sync>;
 
 pub struct Socket {
 	pub src_addr: Ipv4Addr,
@@ -15,21 +33,77 @@ pub struct Socket {
 	pub recv_param: RecvParam,
 	pub status: TcpStatus,
 	pub buffer: Vec<u8>,
+	pub reassembly: ReassemblyQueue,
+	pub send_buffer: VecDeque<u8>, //未送信データ
+	pub retransmission_queue: VecDeque<RetransmissionEntry>,
+	pub timer: RetransmissionTimer,
+	pub persist_timer: PersistTimer,
+	pub keepalive: KeepaliveTimer,
+	pub congestion_algorithm: CongestionAlgorithm,
+	pub congestion: Box<dyn CongestionControl>,
+	pub rate_sampler: RateSampler,
+	pub pacing_next: Instant, //ペーシング時に次のセグメントを送ってよい時刻
+	pub nodelay: bool,        //trueならNagleアルゴリズムを使わない
+	pub time_wait_expires_at: Option<Instant>, //TIME_WAITを抜けるか、CLOSEDで読まれないデータを諦めてテーブルから消す時刻
+	pub fin_pending: bool, //送信バッファを送り切ったらFINを送る
+	pub read_closed: bool, //shutdownで受信側を閉じた。届いたデータは捨てる
+	pub on_close: Option<CloseCallback>,
+}
+
+// 未ACKセグメントの再送用コピー
+pub struct RetransmissionEntry {
+	pub seq: SeqNum,
+	pub flag: u16,
+	pub payload: Vec<u8>,
+	pub sent_at: Instant,
+	pub retransmit_count: u32,
+	pub sacked: bool, //SACKブロックで受信済みと通知された
+	pub delivery: DeliverySnapshot,
+}
+
+impl RetransmissionEntry {
+	// SYN/FINもシーケンス番号を1つ消費する
+	pub fn seq_len(&self) -> u32 {
+		let mut len = self.payload.len() as u32;
+		if self.flag & (TcpFlags::SYN | TcpFlags::FIN) > 0 {
+			len += 1;
+		}
+		len
+	}
 }
 
 #[derive(Clone)]
 pub struct SendParam {
-	pub una: u32,  //未ACK送信
-	pub next: u32, //次の送信
-	pub window: u16,
-	pub iss: u32, //初期送信シーケンス番号
+	pub una: SeqNum,  //未ACK送信
+	pub next: SeqNum, //次の送信
+	pub window: u32, //自分の受信ウィンドウ
+	pub iss: SeqNum, //初期送信シーケンス番号
+	pub wl1: SeqNum, //最後にウィンドウを更新したセグメントのseq
+	pub wl2: SeqNum, //最後にウィンドウを更新したセグメントのack
+	pub mss: usize,  //相手のMSSと自分のMSSの小さい方
+	pub wscale: u8,  //送信するウィンドウのシフト数
+	pub ts_base: Instant, //TSvalの時計の起点
+	pub ts_offset: u32,   //TSvalの初期値
+	pub sack_permitted: bool, //双方がSACK-permittedを送った
+	pub recover: Option<SeqNum>, //ロス回復中なら、回復開始時のSND.NXT
+	pub dup_acks: usize,
+	pub inflation: usize, //高速回復中に重複ACKで膨らませたウィンドウ (RFC 6582)
+	pub timed_out: bool, //再送タイムアウト後、recoverまでACKされるのを待っている
+	pub recv_buffer_size: usize, //受信バッファの上限。windowはこの空きから計算する
+	pub my_mss: usize, //SYNで広告する自分のMSS
 }
 
 #[derive(Clone)]
 pub struct RecvParam {
-	pub next: u32,
-	pub window: u16,
-	pub irs: u32, //初期受信シーケンスno
+	pub next: SeqNum,
+	pub window: u32, //相手の受信ウィンドウ
+	pub irs: SeqNum, //初期受信シーケンスno
+	pub wscale: Option<u8>, //受信したウィンドウのシフト数。相手がオプションを送らなければNone
+	pub ts_recent: Option<u32>, //次に送るTSecr。タイムスタンプを使わなければNone
+	pub ts_recent_at: Instant,  //ts_recentを更新した時刻
+	pub last_window_field: u16, //最後に受け取ったヘッダのウィンドウ。重複ACKの判定に使う
+	pub unacked_segments: usize, //まだACKを返していない受信セグメントの数
+	pub ack_pending_since: Option<Instant>, //ACKを遅らせ始めた時刻
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -40,6 +114,7 @@ pub enum TcpStatus {
 	Established,
 	FinWait1,
 	FinWait2,
+	CloseWait,
 	Closing,
 	LastAck,
 	TimeWait,
@@ -55,6 +130,7 @@ impl Debug for TcpStatus {
 			TcpStatus::Established => write!(f, "ESTABLISHED"),
 			TcpStatus::FinWait1 => write!(f, "FINWAIT1"),
 			TcpStatus::FinWait2 => write!(f, "FINWAIT2"),
+			TcpStatus::CloseWait => write!(f, "CLOSEWAIT"),
 			TcpStatus::Closing => write!(f, "CLOSING"),
 			TcpStatus::LastAck => write!(f, "LASTACK"),
 			TcpStatus::TimeWait => write!(f, "TIMEWAIT"),
@@ -70,11 +146,511 @@ impl Socket {
 		flag: u16,
 		payload: Option<&[u8]>,
 	) -> Result<(), failure::Error> {
-		let mut payload_len = 0;
+		let seq = self.send_param.next;
+		self.transmit(ts, seq, flag, payload)?;
+		let now = Instant::now();
+		let entry = RetransmissionEntry {
+			seq,
+			flag,
+			payload: payload.unwrap_or(&[]).to_vec(),
+			sent_at: now,
+			retransmit_count: 0,
+			sacked: false,
+			delivery: self.rate_sampler.on_send(self.retransmission_queue.is_empty(), now),
+		};
+		let seq_len = entry.seq_len();
+		if seq_len > 0 {
+			self.send_param.next = seq + seq_len;
+			self.retransmission_queue.push_back(entry);
+			self.timer.start(Instant::now());
+		}
+		Ok(())
+	}
+
+	// 再送キューのうちSACKされていない最初のセグメントを再送する (RFC 6298 5.4)
+	pub fn retransmit(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		let index = self.retransmission_queue.iter().position(|entry| !entry.sacked).unwrap_or(0);
+		self.retransmit_entry(ts, index)
+	}
+
+	// SACKで後続のセグメントがDUP_THRESH個以上届いていると分かった未再送のセグメントを再送する (RFC 6675 IsLost)
+	pub fn retransmit_lost(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		let mut sacked_above = 0;
+		let mut lost = Vec::new();
+		let mut lost_bytes = 0;
+		for (i, entry) in self.retransmission_queue.iter().enumerate().rev() {
+			if entry.sacked {
+				sacked_above += 1;
+			} else if sacked_above >= DUP_THRESH && entry.retransmit_count == 0 {
+				lost.push(i);
+				lost_bytes += entry.seq_len() as usize;
+			}
+		}
+		if lost.is_empty() {
+			return Ok(());
+		}
+		// 失われたセグメントはネットワーク上に無いのでpipeに数えない (RFC 6675 SetPipe)
+		let mut pipe = self.pipe() - lost_bytes;
+		// 1ウィンドウにつき1回だけウィンドウを減らし、最初の1つはcwndに関係なく再送する (RFC 6675 5 (4.3))
+		let mut forced = false;
+		if self.send_param.recover.is_none() {
+			debug!("enter loss recovery: una {}, next {}", self.send_param.una, self.send_param.next);
+			let in_flight = self.pipe();
+			self.congestion.on_congestion_event(in_flight);
+			self.send_param.recover = Some(self.send_param.next);
+			forced = true;
+		}
+		for i in lost.into_iter().rev() {
+			// cwnd - pipe >= 1 SMSS の間だけ再送する (RFC 6675 5 (C))
+			let cwnd = self.congestion.cwnd() + self.send_param.inflation;
+			if !forced && cwnd < pipe + self.send_param.mss {
+				break;
+			}
+			forced = false;
+			pipe += self.retransmission_queue[i].seq_len() as usize;
+			self.retransmit_entry(ts, i)?;
+		}
+		Ok(())
+	}
+
+	// 重複ACKか (RFC 5681 2)
+	pub fn is_duplicate_ack(&self, ack: SeqNum, payload_len: usize, flag: u16, window_field: u16) -> bool {
+		ack == self.send_param.una
+			&& payload_len == 0
+			&& flag & (TcpFlags::SYN | TcpFlags::FIN) == 0
+			&& !self.retransmission_queue.is_empty()
+			&& window_field == self.recv_param.last_window_field
+	}
+
+	// 3つ目の重複ACKで高速再送し、高速回復に入る (RFC 5681 3.2, RFC 6582 3.2)
+	pub fn on_duplicate_ack(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		self.send_param.dup_acks += 1;
+		if self.send_param.recover.is_some() {
+			// SACKが使えなければ、重複ACKごとに1セグメントがネットワークを出たとみなす
+			if !self.send_param.sack_permitted && !self.send_param.timed_out {
+				self.send_param.inflation += self.send_param.mss;
+			}
+			return Ok(());
+		}
+		if self.send_param.dup_acks == DUP_THRESH {
+			debug!("fast retransmit: una {}", self.send_param.una);
+			let pipe = self.pipe();
+			self.congestion.on_congestion_event(pipe);
+			self.send_param.recover = Some(self.send_param.next);
+			if !self.send_param.sack_permitted {
+				self.send_param.inflation = DUP_THRESH * self.send_param.mss;
+			}
+			self.retransmit(ts)?;
+		}
+		Ok(())
+	}
+
+	// 回復中の部分ACKでは次の穴を再送する (RFC 6582 3.2 step 5)
+	pub fn retransmit_on_partial_ack(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		if self.send_param.recover.is_none() {
+			return Ok(());
+		}
+		let index = self.retransmission_queue.iter().position(|entry| !entry.sacked);
+		match index {
+			Some(i) if self.retransmission_queue[i].retransmit_count == 0 => self.retransmit_entry(ts, i),
+			_ => Ok(()),
+		}
+	}
+
+	// 回復を終える
+	pub fn reset_recovery(&mut self) {
+		self.send_param.recover = None;
+		self.send_param.dup_acks = 0;
+		self.send_param.inflation = 0;
+		self.send_param.timed_out = false;
+	}
+
+	// タイムアウトしたら高速回復をやめ、送信済みのデータがACKされるまで高速再送しない (RFC 6582 4)
+	pub fn enter_timeout_recovery(&mut self) {
+		self.send_param.recover = Some(self.send_param.next);
+		self.send_param.dup_acks = 0;
+		self.send_param.inflation = 0;
+		self.send_param.timed_out = true;
+	}
+
+	fn retransmit_entry(&mut self, ts: &mut TransportSender, index: usize) -> Result<(), failure::Error> {
+		let nothing_in_flight = self.pipe() == 0;
+		let (seq, flag, payload) = match self.retransmission_queue.get_mut(index) {
+			Some(entry) => {
+				entry.retransmit_count += 1;
+				entry.delivery = self.rate_sampler.on_send(nothing_in_flight, Instant::now());
+				(entry.seq, entry.flag, entry.payload.clone())
+			}
+			None => return Ok(()),
+		};
+		debug!("retransmit seq: {}, len: {}", seq, payload.len());
+		self.transmit(ts, seq, flag, Some(&payload))
+	}
+
+	// SACKブロックで受信済みと分かったセグメントに印を付ける
+	pub fn update_scoreboard(&mut self, blocks: &[(SeqNum, SeqNum)]) {
+		if !self.send_param.sack_permitted {
+			return;
+		}
+		let now = Instant::now();
+		for &(left, right) in blocks {
+			if left <= self.send_param.una || right > self.send_param.next {
+				continue;
+			}
+			for entry in self.retransmission_queue.iter_mut() {
+				if !entry.sacked && left <= entry.seq && entry.seq + entry.seq_len() <= right {
+					entry.sacked = true;
+					self.rate_sampler.on_delivered(&entry.delivery, entry.seq_len() as usize, now);
+				}
+			}
+		}
+	}
+
+	// 配送レートの標本を輻輳制御に渡す
+	fn report_rate_sample(&mut self) {
+		if let Some(sample) = self.rate_sampler.take_sample() {
+			let pipe = self.pipe();
+			self.congestion.on_rate_sample(&sample, pipe);
+		}
+	}
+
+	// ネットワーク内にあると推定されるバイト数。SACKされた分は除く
+	pub fn pipe(&self) -> usize {
+		self.retransmission_queue
+			.iter()
+			.filter(|entry| !entry.sacked)
+			.map(|entry| entry.seq_len() as usize)
+			.sum()
+	}
+
+	// 相手のウィンドウと輻輳ウィンドウ、ペーシングレートが許す限り未送信データを送る
+	pub fn send_pending(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		let now = Instant::now();
+		let pacing_rate = self.congestion.pacing_rate();
+		// 間が空いた後にまとめて送りすぎないようにする
+		if self.pacing_next + PACING_MAX_BURST < now {
+			self.pacing_next = now.checked_sub(PACING_MAX_BURST).unwrap_or(now);
+		}
+		while !self.send_buffer.is_empty() {
+			if pacing_rate.is_some() && self.pacing_next > now {
+				break;
+			}
+			let in_flight = (self.send_param.next - self.send_param.una) as usize;
+			let window = self.recv_param.window as usize;
+			let usable = min(
+				window.saturating_sub(in_flight),
+				(self.congestion.cwnd() + self.send_param.inflation).saturating_sub(self.pipe()),
+			);
+			if usable == 0 {
+				break;
+			}
+			if self.is_nagle_holding() {
+				break;
+			}
+			let len = min(min(usable, self.max_segment_len()), self.send_buffer.len());
+			let segment: Vec<u8> = self.send_buffer.drain(..len).collect();
+			self.send_tcp_packet(ts, TcpFlags::ACK, Some(&segment))?;
+			if let Some(rate) = pacing_rate {
+				self.pacing_next += Duration::from_micros((len as f64 / rate * 1_000_000.0) as u64);
+			}
+		}
+		// 送信側を閉じていれば、データの後ろにFINを付ける
+		if self.fin_pending && self.send_buffer.is_empty() {
+			self.fin_pending = false;
+			self.send_tcp_packet(ts, TcpFlags::FIN | TcpFlags::ACK, None)?;
+		}
+		let pipe = self.pipe();
+		if self.send_buffer.is_empty() && pipe < self.congestion.cwnd() {
+			self.rate_sampler.on_app_limited(pipe);
+		}
+		// ウィンドウが0で未ACKのセグメントも無ければ、窓が開いたことを知らせるACKは来ないかもしれない
+		if self.send_buffer.is_empty() || self.recv_param.window > 0 {
+			self.persist_timer.stop();
+		} else if self.retransmission_queue.is_empty() {
+			self.persist_timer.start(now, self.timer.rto);
+		}
+		Ok(())
+	}
+
+	pub fn send_window_probe(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		debug!("zero window probe: una {}", self.send_param.una);
+		self.send_probe(ts)?;
+		self.persist_timer.backoff(Instant::now(), self.timer.rto);
+		Ok(())
+	}
+
+	pub fn send_keepalive_probe(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		debug!("keepalive probe: una {}", self.send_param.una);
+		self.send_probe(ts)?;
+		self.keepalive.on_probe_sent();
+		Ok(())
+	}
+
+	// 受信済みの番号で空のセグメントを送り、相手に現在のACKとウィンドウを返させる
+	fn send_probe(&mut self, ts: &mut TransportSender) -> Result<(), failure::Error> {
+		let seq = self.send_param.una - 1;
+		self.transmit(ts, seq, TcpFlags::ACK, None)
+	}
+
+	// データを受け取ったときのACKを遅らせる。すぐ返すべきならtrue (RFC 1122 4.2.3.2, RFC 5681 4.2)
+	// 2セグメント目と、順序外または穴を埋めるデータにはすぐ返す
+	pub fn delay_ack(&mut self, in_order: bool) -> bool {
+		self.recv_param.unacked_segments += 1;
+		if !in_order || self.recv_param.unacked_segments >= 2 {
+			return true;
+		}
+		if self.recv_param.ack_pending_since.is_none() {
+			self.recv_param.ack_pending_since = Some(Instant::now());
+		}
+		false
+	}
+
+	pub fn has_pending_ack(&self) -> bool {
+		self.recv_param.unacked_segments > 0
+	}
+
+	// 受信データを再構築キューに入れ、RCV.NXTから連続した分をバッファに渡す
+	pub fn receive_data(&mut self, seq: SeqNum, payload: &[u8]) {
+		self.reassembly.insert(self.recv_param.next, self.send_param.window, seq, payload);
+		while let Some(data) = self.reassembly.pop(self.recv_param.next) {
+			self.recv_param.next += data.len() as u32;
+			// 読み出し側を閉じていれば捨てるだけなので、ウィンドウはそのままにする
+			if self.read_closed {
+				continue;
+			}
+			// 右端を動かさないよう、RCV.NXTが進んだ分だけ縮める
+			self.send_param.window = self.send_param.window.saturating_sub(data.len() as u32);
+			self.buffer.extend_from_slice(&data);
+		}
+	}
+
+	// 読み出しで空いた分だけ受信ウィンドウを広げる。相手に知らせるべきならtrue
+	// 小さな増分は広告しない (RFC 1122 4.2.3.3)
+	pub fn update_recv_window(&mut self) -> bool {
+		let free = self.send_param.recv_buffer_size.saturating_sub(self.buffer.len()) as u32;
+		let granularity = (1u32 << self.advertised_wscale()) - 1;
+		let window = min(free, self.max_recv_window()) & !granularity;
+		let threshold = min(self.send_param.recv_buffer_size / 2, self.send_param.mss) as u32;
+		if window < self.send_param.window + threshold {
+			return false;
+		}
+		debug!("recv window: {} -> {}", self.send_param.window, window);
+		self.send_param.window = window;
+		true
+	}
+
+	// ウィンドウフィールドに載せるシフト数。相手がウィンドウスケールを使わなければ0
+	fn advertised_wscale(&self) -> u8 {
+		match self.recv_param.wscale {
+			Some(_) => self.send_param.wscale,
+			None => 0,
+		}
+	}
+
+	fn max_recv_window(&self) -> u32 {
+		(u16::MAX as u32) << self.advertised_wscale()
+	}
+
+	// SYNで受け取ったオプションを反映する
+	pub fn negotiate_options(&mut self, options: &TcpOptions) {
+		let peer_mss = options.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+		self.send_param.mss = max(min(peer_mss, self.send_param.my_mss), MIN_MSS);
+		// 双方がウィンドウスケールを送った場合だけ有効になる (RFC 7323 2.2)
+		self.recv_param.wscale = options.wscale.map(|shift| min(shift, option::MAX_WSCALE));
+		self.recv_param.ts_recent = options.timestamps.map(|(tsval, _)| tsval);
+		self.recv_param.ts_recent_at = Instant::now();
+		self.send_param.sack_permitted = options.sack_permitted;
+		self.send_param.window = min(self.send_param.window, self.max_recv_window());
+		self.congestion.init(self.send_param.mss);
+		debug!(
+			"mss: {}, wscale: {:?}, timestamps: {}, sack: {}",
+			self.send_param.mss,
+			self.recv_param.wscale,
+			self.recv_param.ts_recent.is_some(),
+			self.send_param.sack_permitted
+		);
+	}
+
+	// タイムスタンプオプションを使うか
+	pub fn timestamps_enabled(&self) -> bool {
+		self.recv_param.ts_recent.is_some()
+	}
+
+	// TSvalに使う1ms単位の時計
+	pub fn timestamp(&self) -> u32 {
+		self.send_param.ts_offset.wrapping_add(self.send_param.ts_base.elapsed().as_millis() as u32)
+	}
+
+	// TS.Recentを更新し、エコーされたTSecrからRTTを計測する (RFC 7323 4.3)
+	pub fn update_timestamps(&mut self, seq: SeqNum, ack: SeqNum, options: &TcpOptions) {
+		let (tsval, tsecr) = match options.timestamps {
+			Some(timestamps) if self.timestamps_enabled() => timestamps,
+			_ => return,
+		};
+		if seq <= self.recv_param.next {
+			self.recv_param.ts_recent = Some(tsval);
+			self.recv_param.ts_recent_at = Instant::now();
+		}
+		// 再送したセグメントへのACKでも正しいRTTが得られる
+		if ack > self.send_param.una && ack <= self.send_param.next && tsecr != 0 {
+			let rtt = self.timestamp().wrapping_sub(tsecr);
+			self.timer.on_rtt_sample(Duration::from_millis(rtt as u64));
+		}
+	}
+
+	// 2MSLの間、遅れて届くセグメントと相手のFINの再送を受け止める (RFC 793 3.5)
+	// FINの再送を受けたら呼び直して数え直す
+	pub fn enter_time_wait(&mut self, duration: Duration) {
+		self.status = TcpStatus::TimeWait;
+		self.timer.stop();
+		self.persist_timer.stop();
+		self.retransmission_queue.clear();
+		self.time_wait_expires_at = Some(Instant::now() + duration);
+	}
+
+	// 相手のFINを受け取っていれば、バッファを読み終えたところがEOF
+	pub fn is_peer_closed(&self) -> bool {
+		matches!(
+			self.status,
+			TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::Closing | TcpStatus::TimeWait | TcpStatus::Closed
+		)
+	}
+
+	// 送ったFINまでACKされたか
+	pub fn is_fin_acked(&self) -> bool {
+		!self.fin_pending && self.send_param.una == self.send_param.next
+	}
+
+	pub fn is_time_wait_expired(&self, now: Instant) -> bool {
+		self.status == TcpStatus::TimeWait && self.time_wait_expires_at.is_some_and(|expires_at| now >= expires_at)
+	}
+
+	// 未ACKのデータがある間は1セグメントに満たないデータを送らず溜める (RFC 1122 4.2.3.4)
+	pub fn is_nagle_holding(&self) -> bool {
+		!self.nodelay
+			&& !self.fin_pending
+			&& self.send_buffer.len() < self.max_segment_len()
+			&& self.send_param.una != self.send_param.next
+	}
+
+	// オプションを除いた1セグメントに載せられるデータ長
+	fn max_segment_len(&self) -> usize {
+		// オプションがMSS以上になっても1バイトは送れるようにする
+		max(self.send_param.mss.saturating_sub(self.options_for(TcpFlags::ACK).to_bytes().len()), 1)
+	}
+
+	// 送信するセグメントに付けるオプション
+	fn options_for(&self, flag: u16) -> TcpOptions {
+		let mut options = TcpOptions::default();
+		if flag & TcpFlags::SYN > 0 {
+			options.mss = Some(self.send_param.my_mss as u16);
+			// SYN-ACKには相手が送ってきた場合だけ付ける
+			if flag & TcpFlags::ACK == 0 || self.recv_param.wscale.is_some() {
+				options.wscale = Some(self.send_param.wscale);
+			}
+			options.sack_permitted = flag & TcpFlags::ACK == 0 || self.send_param.sack_permitted;
+		}
+		// 有効になった後は全てのセグメントに付ける
+		if self.timestamps_enabled() || (flag & TcpFlags::SYN > 0 && flag & TcpFlags::ACK == 0) {
+			options.timestamps = Some((self.timestamp(), self.recv_param.ts_recent.unwrap_or(0)));
+		}
+		// 順序が入れ替わったデータを持っていればSACKブロックで知らせる
+		if flag & TcpFlags::SYN == 0 && self.send_param.sack_permitted && !self.reassembly.is_empty() {
+			options.sack_blocks = self.reassembly.sack_blocks(options.sack_blocks_capacity());
+		}
+		options
+	}
+
+	// ヘッダに書くウィンドウ。SYNのウィンドウはスケールしない
+	fn window_field(&self, flag: u16) -> u16 {
+		if flag & TcpFlags::SYN > 0 {
+			return min(self.send_param.window, u16::MAX as u32) as u16;
+		}
+		(self.send_param.window >> self.advertised_wscale()) as u16
+	}
+
+	// 相手の広告ウィンドウを更新する (RFC 793 SND.WL1/SND.WL2)
+	pub fn update_window(&mut self, seq: SeqNum, ack: SeqNum, window: u16) {
+		let window = (window as u32) << self.recv_param.wscale.unwrap_or(0);
+		let param = &mut self.send_param;
+		if ack < param.una || ack > param.next {
+			return;
+		}
+		if param.wl1 < seq || (param.wl1 == seq && param.wl2 <= ack) {
+			self.recv_param.window = window;
+			param.wl1 = seq;
+			param.wl2 = ack;
+		}
+	}
+
+	// 受信したACK番号で送信済みセグメントを確認応答する。新しいデータがACKされたらtrue
+	pub fn acknowledge(&mut self, ack: SeqNum) -> bool {
+		if ack <= self.send_param.una || ack > self.send_param.next {
+			self.report_rate_sample();
+			return false;
+		}
+		let acked = (ack - self.send_param.una) as usize;
+		let in_flight = self.pipe();
+		self.send_param.una = ack;
+
+		let now = Instant::now();
+		let mut rtt_sample = None;
+		while let Some(entry) = self.retransmission_queue.front() {
+			if ack - entry.seq < entry.seq_len() {
+				break;
+			}
+			// Karn のアルゴリズム: 再送したセグメントはRTT計測に使わない
+			// タイムスタンプが有効ならそちらで計測する
+			if entry.retransmit_count == 0 && !self.timestamps_enabled() {
+				rtt_sample = Some(now - entry.sent_at);
+			}
+			if !entry.sacked {
+				self.rate_sampler.on_delivered(&entry.delivery, entry.seq_len() as usize, now);
+			}
+			self.retransmission_queue.pop_front();
+		}
+		self.report_rate_sample();
+		if let Some(rtt) = rtt_sample {
+			self.timer.on_rtt_sample(rtt);
+		}
+		self.send_param.dup_acks = 0;
+		match self.send_param.recover {
+			Some(recover) if ack >= recover => {
+				debug!("exit loss recovery: una {}", ack);
+				self.reset_recovery();
+				self.congestion.on_recovery_exit();
+			}
+			// タイムアウト後はスロースタートでウィンドウを広げる
+			Some(_) if self.send_param.timed_out => self.congestion.on_ack(acked, in_flight, self.timer.srtt),
+			Some(_) => {
+				// 部分ACKではACKされた分だけ縮め、1セグメント分戻す
+				if !self.send_param.sack_permitted {
+					self.send_param.inflation = self.send_param.inflation.saturating_sub(acked) + self.send_param.mss;
+				}
+			}
+			None => self.congestion.on_ack(acked, in_flight, self.timer.srtt),
+		}
+		// RFC 6298 5.2, 5.3
+		if self.retransmission_queue.is_empty() {
+			self.timer.stop();
+		} else {
+			self.timer.restart(now);
+		}
+		true
+	}
+
+	fn transmit(
+		&mut self,
+		ts: &mut TransportSender,
+		seq: SeqNum,
+		flag: u16,
+		payload: Option<&[u8]>,
+	) -> Result<(), failure::Error> {
+		let options = self.options_for(flag).to_bytes();
 		let mut tcp_buffer = vec![0u8; TCP_SIZE];
+		tcp_buffer.extend_from_slice(&options);
 		if let Some(payload) = payload {
 			tcp_buffer.extend_from_slice(payload);
-			payload_len = payload.len();
 		};
 		let mut tcp_packet = MutableTcpPacket::new(&mut tcp_buffer).unwrap();
 		tcp_packet.set_source(self.src_port);
@@ -83,11 +659,11 @@ impl Socket {
 		} else {
 			return Err(failure::err_msg("missing dest port"));
 		}
-		tcp_packet.set_sequence(self.send_param.una); // TODO: reason
-		tcp_packet.set_acknowledgement(self.recv_param.next);
-		tcp_packet.set_data_offset(5);
+		tcp_packet.set_sequence(seq.0);
+		tcp_packet.set_acknowledgement(self.recv_param.next.0);
+		tcp_packet.set_data_offset(((TCP_SIZE + options.len()) / 4) as u8);
 		tcp_packet.set_flags(flag);
-		tcp_packet.set_window(self.send_param.window);
+		tcp_packet.set_window(self.window_field(flag));
 
 		if let Some(dst_addr) = self.dst_addr {
 			tcp_packet.set_checksum(tcp::ipv4_checksum(
@@ -97,12 +673,19 @@ impl Socket {
 			));
 			ts.send_to(tcp_packet, IpAddr::V4(dst_addr))?;
 		}
-		self.send_param.next = self.send_param.una + payload_len as u32;
+		// ACKを載せて送ったので遅延していたACKは不要になる
+		if flag & TcpFlags::ACK > 0 {
+			self.recv_param.unacked_segments = 0;
+			self.recv_param.ack_pending_since = None;
+		}
 		Ok(())
 	}
 
-	pub fn initialize(my_ip: Ipv4Addr, dst_addr: Option<Ipv4Addr>, my_port: u16, dst_port: Option<u16>, status: TcpStatus) -> Self {
-		let initial_seq = rand::random::<u32>();
+	#[allow(clippy::too_many_arguments)]
+	pub fn initialize(my_ip: Ipv4Addr, dst_addr: Option<Ipv4Addr>, my_port: u16, dst_port: Option<u16>, status: TcpStatus, congestion_algorithm: CongestionAlgorithm, recv_buffer_size: usize, my_mss: usize) -> Self {
+		let initial_seq = SeqNum(rand::random::<u32>());
+		let mut congestion = congestion_algorithm.build();
+		congestion.init(DEFAULT_MSS);
 		Socket {
 			src_addr: my_ip,
 			dst_addr,
@@ -111,20 +694,56 @@ impl Socket {
 			send_param: SendParam {
 				una: initial_seq,
 				next: initial_seq,
-				window: TCP_INIT_WINDOW as u16,
+				window: recv_buffer_size as u32,
 				iss: initial_seq,
+				wl1: initial_seq,
+				wl2: initial_seq,
+				mss: DEFAULT_MSS,
+				wscale: wscale_for(recv_buffer_size as u32),
+				ts_base: Instant::now(),
+				ts_offset: rand::random::<u32>(),
+				sack_permitted: false,
+				recover: None,
+				dup_acks: 0,
+				inflation: 0,
+				timed_out: false,
+				recv_buffer_size,
+				my_mss,
 			},
 			recv_param: RecvParam {
-				next: 0,
+				next: SeqNum(0),
 				window: 0,
-				irs: 0,
+				irs: SeqNum(0),
+				wscale: None,
+				ts_recent: None,
+				ts_recent_at: Instant::now(),
+				last_window_field: 0,
+				unacked_segments: 0,
+				ack_pending_since: None,
 			},
 			status,
 			buffer: Vec::new(),
+			reassembly: ReassemblyQueue::new(),
+			send_buffer: VecDeque::new(),
+			retransmission_queue: VecDeque::new(),
+			timer: RetransmissionTimer::new(),
+			persist_timer: PersistTimer::new(),
+			keepalive: KeepaliveTimer::new(),
+			congestion_algorithm,
+			congestion,
+			rate_sampler: RateSampler::new(),
+			pacing_next: Instant::now(),
+			nodelay: false,
+			time_wait_expires_at: None,
+			fin_pending: false,
+			read_closed: false,
+			on_close: None,
 		}
 	}
 
-	pub fn create_established(my_ip: Ipv4Addr, dst_ip: Ipv4Addr, my_port: u16, dst_port: u16, send_param: &SendParam, recv_param: &RecvParam) -> Self {
+	pub fn create_established(my_ip: Ipv4Addr, dst_ip: Ipv4Addr, my_port: u16, dst_port: u16, send_param: &SendParam, recv_param: &RecvParam, congestion_algorithm: CongestionAlgorithm) -> Self {
+		let mut congestion = congestion_algorithm.build();
+		congestion.init(send_param.mss);
 		Socket {
 			src_addr: my_ip,
 			dst_addr: Some(dst_ip),
@@ -134,6 +753,30 @@ impl Socket {
 			recv_param: recv_param.clone(),
 			status: TcpStatus::Established,
 			buffer: Vec::new(),
+			reassembly: ReassemblyQueue::new(),
+			send_buffer: VecDeque::new(),
+			retransmission_queue: VecDeque::new(),
+			timer: RetransmissionTimer::new(),
+			persist_timer: PersistTimer::new(),
+			keepalive: KeepaliveTimer::new(),
+			congestion_algorithm,
+			congestion,
+			rate_sampler: RateSampler::new(),
+			pacing_next: Instant::now(),
+			nodelay: false,
+			time_wait_expires_at: None,
+			fin_pending: false,
+			read_closed: false,
+			on_close: None,
 		}
 	}
 }
+
+// ウィンドウを16bitに収めるのに必要なシフト数
+fn wscale_for(window: u32) -> u8 {
+	let mut shift = 0;
+	while shift < option::MAX_WSCALE && window >> shift > u16::MAX as u32 {
+		shift += 1;
+	}
+	shift
+}
@@ -2,52 +2,104 @@ use pnet::packet::tcp::{TcpFlags, TcpPacket};
 use pnet::packet::Packet;
 use pnet::transport::{self, TransportSender};
 use std::collections::{HashMap, VecDeque};
-use std::net::{IpAddr, Ipv4Addr};
-use std::sync::{Arc, RwLock};
+use std::net::{IpAddr, Ipv4Addr, Shutdown};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 extern crate rand;
 use rand::Rng;
-use std::cmp::min;
 
-use super::socket::{Socket, TcpStatus};
+use super::congestion::CongestionAlgorithm;
+use super::error::TcpError;
+use super::option::TcpOptions;
+use super::seq::SeqNum;
+use super::socket::{self, CloseCallback, Socket, TcpStatus};
+use super::timer::KeepaliveConfig;
 use super::util;
 
-const HS_RETRY_LIMIT: i32 = 3;
-const FIN_RETRY_LIMIT: i32 = 3;
-const MSS: usize = 1460;
+const RETRANSMISSION_LIMIT: u32 = 5;
 const UNDEFINED_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const UNDEFINED_PORT: u16 = 0;
 const WAIT_MS: u64 = 100;
+const TIMER_TICK_MS: u64 = 10;
+const DEFAULT_DELAYED_ACK_MS: u64 = 40;
+const MAX_DELAYED_ACK_MS: u64 = 500; // RFC 1122 4.2.3.2
+const DEFAULT_RECV_BUFFER_SIZE: usize = 256 * 1024;
+const DEFAULT_MSL_MS: u64 = 30 * 1000;
+const MAX_MSS: usize = 65535 - 40; // IPv4の最大長からIPとTCPのヘッダを除いた分
+const RST_PER_SEC: u32 = 100;
 
-type SockId = (Ipv4Addr, u16);
+type SockId = (Ipv4Addr, u16, u16); //(相手のアドレス, 相手のポート, 自分のポート)
 
 pub struct TCPManager {
 	my_ip: Ipv4Addr,
-	//srcPortがキー(1ポートでしか受けられない) (相手のaddr, portのタプルをキーにしたら？)
+	//リスニングソケットは相手のアドレスとポートを未定義にしたキーで登録する
 	connections: RwLock<HashMap<SockId, Socket>>,
-	backlog: RwLock<VecDeque<Socket>>
+	backlog: RwLock<VecDeque<Socket>>,
+	delayed_ack_timeout: Duration,
+	recv_buffer_size: usize,
+	mss: usize, //自分が受け取れるMSS。MTUの小さい経路ではこれを下げる
+	time_wait_duration: Duration, //2MSL
+	reset_limiter: Mutex<util::RateLimiter>,
+	aborted: RwLock<HashMap<SockId, TcpError>>, //切れた接続と理由。releaseかstream_idの再利用まで残す
 }
 
 impl TCPManager {
 	pub fn init() -> Result<Arc<Self>, failure::Error> {
 		let config = util::load_env();
+		let delayed_ack_ms = match config.get("DELAYED_ACK_MS") {
+			Some(ms) => ms.parse::<u64>()?,
+			None => DEFAULT_DELAYED_ACK_MS,
+		};
+		let recv_buffer_size = match config.get("RECV_BUFFER_SIZE") {
+			Some(size) => size.parse::<usize>()?,
+			None => DEFAULT_RECV_BUFFER_SIZE,
+		};
+		let msl_ms = match config.get("MSL_MS") {
+			Some(ms) => ms.parse::<u64>()?,
+			None => DEFAULT_MSL_MS,
+		};
+		let mss = match config.get("MSS") {
+			Some(mss) => mss.parse::<usize>()?,
+			None => socket::MSS,
+		};
+		if !(socket::MIN_MSS..=MAX_MSS).contains(&mss) {
+			return Err(failure::err_msg(format!("MSS must be between {} and {}", socket::MIN_MSS, MAX_MSS)));
+		}
 
 		let manager = Arc::new(TCPManager {
 			my_ip: config.get("IP_ADDR").expect("missing IP_ADDR").parse()?,
 			connections: RwLock::new(HashMap::new()),
 			backlog: RwLock::new(VecDeque::new()),
+			delayed_ack_timeout: Duration::from_millis(delayed_ack_ms.min(MAX_DELAYED_ACK_MS)),
+			recv_buffer_size,
+			mss,
+			time_wait_duration: Duration::from_millis(msl_ms * 2),
+			reset_limiter: Mutex::new(util::RateLimiter::new(RST_PER_SEC)),
+			aborted: RwLock::new(HashMap::new()),
 		});
 		let cloned = manager.clone();
 		thread::spawn(move || cloned.recv_handler());
+		let cloned = manager.clone();
+		thread::spawn(move || cloned.timer_handler());
 		Ok(manager)
 	}
 
 	pub fn listen(&self, client_port: u16) -> Result<SockId, failure::Error> {
-		let socket = Socket::initialize(self.my_ip, None, client_port, None, TcpStatus::Listen);
+		self.listen_with_congestion_control(client_port, CongestionAlgorithm::default())
+	}
+
+	// 受け付けた接続は全て指定した輻輳制御を使う
+	pub fn listen_with_congestion_control(
+		&self,
+		client_port: u16,
+		algorithm: CongestionAlgorithm,
+	) -> Result<SockId, failure::Error> {
+		let socket = Socket::initialize(self.my_ip, None, client_port, None, TcpStatus::Listen, algorithm, self.recv_buffer_size, self.mss);
 		let mut table_lock = self.connections.write().unwrap();
-		table_lock.insert((UNDEFINED_ADDR, UNDEFINED_PORT), socket);
-		Ok((UNDEFINED_ADDR, UNDEFINED_PORT))
+		let stream_id = (UNDEFINED_ADDR, UNDEFINED_PORT, client_port);
+		table_lock.insert(stream_id, socket);
+		Ok(stream_id)
 	}
 
 	pub fn accept(&self) -> SockId {
@@ -56,7 +108,8 @@ impl TCPManager {
 			let mut que_lock = self.backlog.write().unwrap();
 			if !que_lock.is_empty() {
 				let sock = que_lock.pop_front().unwrap();
-				let stream_id = (sock.dst_addr.unwrap(), sock.dst_port.unwrap());
+				let stream_id = (sock.dst_addr.unwrap(), sock.dst_port.unwrap(), sock.src_port);
+				self.aborted.write().unwrap().remove(&stream_id);
 				table_lock.insert(stream_id, sock);
 				debug!("connection established: {:?}", stream_id);
 				return stream_id;
@@ -68,130 +121,363 @@ impl TCPManager {
 	}
 
 	pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockId, failure::Error> {
+		self.connect_with_congestion_control(addr, port, CongestionAlgorithm::default())
+	}
+
+	pub fn connect_with_congestion_control(
+		&self,
+		addr: Ipv4Addr,
+		port: u16,
+		algorithm: CongestionAlgorithm,
+	) -> Result<SockId, failure::Error> {
 		let mut rng = rand::thread_rng();
 		let my_port = rng.gen_range(50000, 65000);
+		self.open(addr, port, my_port, algorithm)
+	}
 
-		let socket = Socket::initialize(self.my_ip, Some(addr), my_port, Some(port), TcpStatus::Closed);
+	// 互いに相手のポートを知っていれば、両方からconnectしても1つの接続になる (同時オープン)
+	pub fn connect_from(&self, my_port: u16, addr: Ipv4Addr, port: u16) -> Result<SockId, failure::Error> {
+		self.connect_from_with_congestion_control(my_port, addr, port, CongestionAlgorithm::default())
+	}
+
+	pub fn connect_from_with_congestion_control(
+		&self,
+		my_port: u16,
+		addr: Ipv4Addr,
+		port: u16,
+		algorithm: CongestionAlgorithm,
+	) -> Result<SockId, failure::Error> {
+		self.open(addr, port, my_port, algorithm)
+	}
+
+	fn open(
+		&self,
+		addr: Ipv4Addr,
+		port: u16,
+		my_port: u16,
+		algorithm: CongestionAlgorithm,
+	) -> Result<SockId, failure::Error> {
+		let socket = Socket::initialize(self.my_ip, Some(addr), my_port, Some(port), TcpStatus::Closed, algorithm, self.recv_buffer_size, self.mss);
+		let stream_id = (addr, port, my_port);
 		let mut table_lock = self.connections.write().unwrap();
-		table_lock.insert((addr, port), socket);
+		// 同じ4-tupleがTIME_WAITの間は、古い接続のセグメントと紛れるので使わない
+		match table_lock.get(&stream_id).map(|socket| socket.status) {
+			Some(TcpStatus::TimeWait) => {
+				return Err(failure::err_msg(format!("connection {}->{}:{} is in TIME_WAIT", my_port, addr, port)));
+			}
+			Some(_) => return Err(failure::err_msg("stream already exists.")),
+			None => {}
+		}
+		self.aborted.write().unwrap().remove(&stream_id);
+		table_lock.insert(stream_id, socket);
 
 		let (mut ts, _) = util::create_tcp_channel()?;
-		let socket = table_lock.get_mut(&(addr, port)).unwrap();
+		let socket = table_lock.get_mut(&stream_id).unwrap();
 		socket.send_tcp_packet(&mut ts, TcpFlags::SYN, None)?;
 		socket.status = TcpStatus::SynSent;
 
 		drop(table_lock);
 
-		let mut retry_count = 0;
+		// SYNの再送はタイマスレッドが行う
 		loop {
-			thread::sleep(Duration::from_millis(1000));
-			let mut table_lock = self.connections.write().unwrap();
-			let socket = table_lock.get_mut(&(addr, port)).unwrap();
-			if socket.status == TcpStatus::Established {
-				break;
-			}
-			if retry_count > HS_RETRY_LIMIT {
-				return Err(failure::err_msg("tcp syn retry count exceeded"));
+			thread::sleep(Duration::from_millis(WAIT_MS));
+			let table_lock = self.connections.read().unwrap();
+			match table_lock.get(&stream_id) {
+				Some(socket) if socket.status == TcpStatus::Established => break,
+				Some(_) => {}
+				// SYNの再送が尽きたかRSTを受けた
+				None => return Err(self.stream_error(&stream_id)),
 			}
-			socket.send_tcp_packet(&mut ts, TcpFlags::SYN, None)?;
-			retry_count += 1;
 		}
-		Ok((addr, port))
+		Ok(stream_id)
 	}
 
 	pub fn disconnect(&self, stream_id: SockId) -> Result<(), failure::Error> {
-		let (mut ts, _) = util::create_tcp_channel()?;
-		let mut table_lock = self.connections.write().unwrap();
+		self.shutdown(stream_id, Shutdown::Both)
+	}
 
-		match table_lock.get_mut(&stream_id) {
-			None => Err(failure::err_msg("stream was not found.")),
-			Some(socket) => {
-				if socket.status != TcpStatus::Established {
-					Err(failure::err_msg("connection have not been established."))?
+	// disconnectしてから、送信バッファのデータとFINが相手にACKされるまで待つ
+	pub fn close(&self, stream_id: SockId) -> Result<(), failure::Error> {
+		self.disconnect(stream_id)?;
+		loop {
+			let table_lock = self.connections.read().unwrap();
+			match table_lock.get(&stream_id) {
+				Some(socket) if socket.is_fin_acked() => return Ok(()),
+				Some(_) => {}
+				// LAST_ACKから閉じ終わったか、再送が尽きたかRSTを受けた
+				None => {
+					return match self.aborted.read().unwrap().get(&stream_id) {
+						Some(error) => Err((*error).into()),
+						None => Ok(()),
+					};
 				}
-				socket.send_tcp_packet(&mut ts, TcpFlags::FIN | TcpFlags::ACK, None)?;
+			}
+			drop(table_lock);
+			thread::sleep(Duration::from_millis(WAIT_MS));
+		}
+	}
+
+	// std::net::TcpStream::shutdownと同じ。Writeでは送信バッファのデータを送った後にFINを送る
+	pub fn shutdown(&self, stream_id: SockId, how: Shutdown) -> Result<(), failure::Error> {
+		let (mut ts, _) = util::create_tcp_channel()?;
+		let mut table_lock = self.connections.write().unwrap();
+		let socket = match table_lock.get_mut(&stream_id) {
+			Some(socket) => socket,
+			None => return Err(self.stream_error(&stream_id)),
+		};
+		if how != Shutdown::Write {
+			socket.read_closed = true;
+			socket.buffer.clear();
+		}
+		if how == Shutdown::Read {
+			return Ok(());
+		}
+		// FINの再送とTIME_WAIT後の削除はタイマスレッドが行う
+		match socket.status {
+			TcpStatus::Established => {
+				socket.fin_pending = true;
 				socket.status = TcpStatus::FinWait1;
-				drop(table_lock);
-				let mut retry_count = 0;
-				let mut timewait_count = 0;
-				loop {
-					thread::sleep(Duration::from_millis(1000));
-					let mut table_lock = self.connections.write().unwrap();
-					let mut socket = table_lock.get_mut(&stream_id).unwrap();
-
-					if socket.status == TcpStatus::TimeWait {
-						if timewait_count > 1 {
-							socket.status = TcpStatus::Closed;
-							break;
-						}
-						timewait_count += 1;
-						continue;
-					}
-					if retry_count > FIN_RETRY_LIMIT {
-						return Err(failure::err_msg("fin retry limit exceeded"));
-					}
-					socket.send_tcp_packet(&mut ts, TcpFlags::FIN, None)?;
-					retry_count += 1;
-				}
-				let mut table_lock = self.connections.write().unwrap();
-				table_lock.remove(&stream_id).unwrap();
-				debug!("stream_id: {:?} closed", stream_id);
-				Ok(())
+				socket.send_pending(&mut ts)
+			}
+			TcpStatus::CloseWait => {
+				socket.fin_pending = true;
+				socket.status = TcpStatus::LastAck;
+				socket.send_pending(&mut ts)
 			}
+			// 既に閉じている
+			TcpStatus::FinWait1
+			| TcpStatus::FinWait2
+			| TcpStatus::Closing
+			| TcpStatus::LastAck
+			| TcpStatus::TimeWait
+			| TcpStatus::Closed => Ok(()),
+			_ => Err(failure::err_msg("connection have not been established.")),
 		}
 	}
 
 	pub fn send(&self, stream_id: SockId, payload: &[u8]) -> Result<(), failure::Error> {
 		let (mut ts, _) = util::create_tcp_channel()?;
-		let table_lock = self.connections.read().unwrap();
-		if !table_lock.contains_key(&stream_id) {
-			return Err(failure::err_msg("connection have not been established."));
+		let mut table_lock = self.connections.write().unwrap();
+		let socket = match table_lock.get_mut(&stream_id) {
+			Some(socket) => socket,
+			None => return Err(self.stream_error(&stream_id)),
+		};
+		match socket.status {
+			TcpStatus::Established | TcpStatus::CloseWait => {}
+			TcpStatus::FinWait1
+			| TcpStatus::FinWait2
+			| TcpStatus::Closing
+			| TcpStatus::LastAck
+			| TcpStatus::TimeWait
+			| TcpStatus::Closed => {
+				return Err(TcpError::BrokenPipe.into());
+			}
+			_ => Err(failure::err_msg("connection have not been established."))?,
 		}
+		// ウィンドウが許す分だけ送り、残りはACK受信時に送る
+		socket.send_buffer.extend(payload);
+		socket.send_pending(&mut ts)?;
+		drop(table_lock);
 
-		let socket = table_lock.get(&stream_id).unwrap();
-		if socket.status != TcpStatus::Established {
-			Err(failure::err_msg("connection have not been established."))?
+		// 全データを送信し終えるまで待つ。Nagleで溜めている分はACK受信時に送る。再送はタイマスレッドが行う
+		loop {
+			let table_lock = self.connections.read().unwrap();
+			// 再送が尽きるかRSTを受けると接続は消える
+			let socket = match table_lock.get(&stream_id) {
+				Some(socket) => socket,
+				None => return Err(self.stream_error(&stream_id)),
+			};
+			if socket.send_buffer.is_empty() || socket.is_nagle_holding() {
+				break;
+			}
+			drop(table_lock);
+			thread::sleep(Duration::from_millis(TIMER_TICK_MS));
 		}
-		let mut unsent_len = payload.len();
-		let mut len_to_be_sent;
-		let mut left = 0;
-		let max_len = min(socket.recv_param.window as usize, MSS);
+		Ok(())
+	}
 
-		drop(table_lock);
+	// 接続を破棄し、以降のread/send/disconnectで理由を返せるよう残しておく
+	// コールバックはテーブルのロックを外してから呼ぶ
+	fn abort(&self, table: &mut HashMap<SockId, Socket>, stream_id: SockId, error: TcpError) -> Option<CloseCallback> {
+		warn!("connection aborted: {:?}: {}", stream_id, error);
+		self.aborted.write().unwrap().insert(stream_id, error);
+		table.remove(&stream_id).and_then(|socket| socket.on_close)
+	}
 
-		while unsent_len > 0 {
-			if unsent_len > max_len {
-				len_to_be_sent = max_len;
-			} else {
-				len_to_be_sent = unsent_len;
+	// テーブルに無い接続を使おうとしたときのエラー
+	fn stream_error(&self, stream_id: &SockId) -> failure::Error {
+		match self.aborted.read().unwrap().get(stream_id) {
+			Some(error) => (*error).into(),
+			None => TcpError::NotConnected.into(),
+		}
+	}
+
+	// 切れた接続を使い終わったら呼び、残しておいた理由を捨てる
+	pub fn release(&self, stream_id: SockId) {
+		self.aborted.write().unwrap().remove(&stream_id);
+	}
+
+	// RSTを返す。相手に使われてRSTの嵐にならないよう数を制限する
+	fn reset(&self, ts: &mut TransportSender, dst_addr: Ipv4Addr, recv_packet: &TcpPacket) -> Result<(), failure::Error> {
+		if !util::is_correct_checksum(recv_packet, &dst_addr, &self.my_ip) {
+			return Ok(());
+		}
+		if !self.reset_limiter.lock().unwrap().allow(Instant::now()) {
+			debug!("rst rate limited: {}:{}", dst_addr, recv_packet.get_source());
+			return Ok(());
+		}
+		util::send_reset(ts, &self.my_ip, &dst_addr, recv_packet)
+	}
+
+	// 小さい書き込みをまとめずにすぐ送るか
+	pub fn set_nodelay(&self, stream_id: SockId, nodelay: bool) -> Result<(), failure::Error> {
+		let mut table_lock = self.connections.write().unwrap();
+		match table_lock.get_mut(&stream_id) {
+			Some(socket) => {
+				socket.nodelay = nodelay;
+				Ok(())
 			}
-			let mut retry_count = 0;
-			loop {
-				if retry_count > 3 {
-					return Err(failure::err_msg("senddata retry limit exceeded."));
-				}
-				let mut table_lock = self.connections.write().unwrap();
-				let socket = table_lock.get_mut(&stream_id).unwrap();
-				socket.send_tcp_packet(
-					&mut ts,
-					TcpFlags::ACK,
-					Some(&payload[left..left + len_to_be_sent]),
-				)?;
-				drop(table_lock);
-				thread::sleep(Duration::from_millis(WAIT_MS));
+			None => Err(self.stream_error(&stream_id)),
+		}
+	}
 
-				let table_lock = self.connections.read().unwrap();
-				let socket = table_lock.get(&stream_id).unwrap();
+	// 接続が閉じるかRSTやタイムアウトで切れたときに呼ばれる関数を登録する
+	// 呼ばれるのはタイマスレッドか受信スレッドなので、長い処理はしない
+	pub fn set_close_callback<F>(&self, stream_id: SockId, callback: F) -> Result<(), failure::Error>
+	where
+		F: FnOnce(Option<TcpError>) + Send + Sync + 'static,
+	{
+		let mut table_lock = self.connections.write().unwrap();
+		match table_lock.get_mut(&stream_id) {
+			Some(socket) => {
+				socket.on_close = Some(Box::new(callback));
+				Ok(())
+			}
+			None => Err(self.stream_error(&stream_id)),
+		}
+	}
 
-				if socket.send_param.una == socket.send_param.next {
-					break;
+	// Noneならキープアライブを止める
+	pub fn set_keepalive(&self, stream_id: SockId, config: Option<KeepaliveConfig>) -> Result<(), failure::Error> {
+		let mut table_lock = self.connections.write().unwrap();
+		match table_lock.get_mut(&stream_id) {
+			Some(socket) => {
+				socket.keepalive.set_config(config, Instant::now());
+				Ok(())
+			}
+			None => Err(self.stream_error(&stream_id)),
+		}
+	}
+
+	pub fn timer_handler(&self) -> Result<(), failure::Error> {
+		let (mut ts, _) = util::create_tcp_channel()?;
+		debug!("begin timer thread");
+		loop {
+			thread::sleep(Duration::from_millis(TIMER_TICK_MS));
+			let now = Instant::now();
+			let mut table_lock = self.connections.write().unwrap();
+			let mut reaped = Vec::new();
+			let mut aborted = Vec::new();
+			for (stream_id, socket) in table_lock.iter_mut() {
+				// TIME_WAITは2MSLで消し、4-tupleを空ける
+				if socket.is_time_wait_expired(now) {
+					reaped.push(*stream_id);
+					continue;
+				}
+				if socket.status == TcpStatus::Closed {
+					// 読まれていないデータが残っていれば、2MSLまでは読まれるのを待つ
+					if socket.buffer.is_empty() || socket.read_closed {
+						reaped.push(*stream_id);
+					} else {
+						match socket.time_wait_expires_at {
+							Some(expires_at) if now >= expires_at => reaped.push(*stream_id),
+							Some(_) => {}
+							None => socket.time_wait_expires_at = Some(now + self.time_wait_duration),
+						}
+					}
+					continue;
+				}
+				// 1つの接続で送信に失敗しても他の接続の処理は続ける
+				match self.on_timer_tick(&mut ts, *stream_id, socket, now) {
+					Ok(true) => aborted.push(*stream_id),
+					Ok(false) => {}
+					Err(e) => error!("timer: {:?}: {}", stream_id, e),
 				}
-				retry_count += 1;
 			}
-			unsent_len -= len_to_be_sent;
-			left += len_to_be_sent;
+			let mut closed = Vec::new();
+			for stream_id in reaped {
+				debug!("stream_id: {:?} closed", stream_id);
+				if let Some(on_close) = table_lock.remove(&stream_id).and_then(|socket| socket.on_close) {
+					closed.push((on_close, None));
+				}
+			}
+			for stream_id in aborted {
+				if let Some(on_close) = self.abort(&mut table_lock, stream_id, TcpError::TimedOut) {
+					closed.push((on_close, Some(TcpError::TimedOut)));
+				}
+			}
+			drop(table_lock);
+			for (on_close, error) in closed {
+				on_close(error);
+			}
 		}
-		Ok(())
+	}
+
+	// 1つの接続のタイマ処理。接続を諦めるならtrue
+	fn on_timer_tick(&self, ts: &mut TransportSender, stream_id: SockId, socket: &mut Socket, now: Instant) -> Result<bool, failure::Error> {
+		if let Some(since) = socket.recv_param.ack_pending_since {
+			if now - since >= self.delayed_ack_timeout {
+				socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+			}
+		}
+		// ペーシングで待たせていたデータを送る
+		let can_send = matches!(
+			socket.status,
+			TcpStatus::Established | TcpStatus::FinWait1 | TcpStatus::CloseWait | TcpStatus::Closing | TcpStatus::LastAck
+		);
+		if can_send && (!socket.send_buffer.is_empty() || socket.fin_pending) {
+			socket.send_pending(ts)?;
+		}
+		if socket.persist_timer.is_expired(now) {
+			socket.send_window_probe(ts)?;
+		}
+		// 未ACKのデータがあれば再送タイマで相手の死を検出できる
+		if (socket.status == TcpStatus::Established || socket.status == TcpStatus::CloseWait)
+			&& socket.retransmission_queue.is_empty()
+			&& socket.keepalive.is_expired(now)
+		{
+			if socket.keepalive.is_exhausted() {
+				warn!("keepalive timed out: {:?}", stream_id);
+				return Ok(true);
+			}
+			socket.send_keepalive_probe(ts)?;
+		}
+		if !socket.timer.is_expired(now) {
+			return Ok(false);
+		}
+		let retransmit_count = socket.retransmission_queue.front().map_or(0, |entry| entry.retransmit_count);
+		if retransmit_count >= RETRANSMISSION_LIMIT {
+			warn!("retransmission limit exceeded: {:?}", stream_id);
+			socket.timer.stop();
+			socket.retransmission_queue.clear();
+			if socket.status == TcpStatus::SynRecv && stream_id.0 == UNDEFINED_ADDR {
+				// リスニングソケットはリッスン状態に戻る
+				*socket = Socket::initialize(self.my_ip, None, socket.src_port, None, TcpStatus::Listen, socket.congestion_algorithm, socket.send_param.recv_buffer_size, socket.send_param.my_mss);
+				return Ok(false);
+			}
+			return Ok(true);
+		}
+		// 同じセグメントの2回目以降のタイムアウトではウィンドウを減らさない (RFC 5681 3.1)
+		if retransmit_count == 0 {
+			let pipe = socket.pipe();
+			socket.congestion.on_timeout(pipe);
+		}
+		socket.enter_timeout_recovery();
+		// RFC 6298 5.4 - 5.6 送信に失敗しても次のタイムアウトまでは再送しない
+		socket.timer.backoff();
+		socket.timer.restart(now);
+		socket.retransmit(ts)?;
+		Ok(false)
 	}
 
 	pub fn recv_handler(&self) -> Result<(), failure::Error> {
@@ -209,63 +495,10 @@ impl TCPManager {
 					if blacklist.contains(&src_addr) {
 						continue;
 					}
-					let mut table_lock = self.connections.write().unwrap();
-					let mut socket = {
-						// recv SYN while listening
-						let sock = table_lock.get_mut(&(src_addr, tcp_packet.get_source()));
-						if sock.is_none() {
-							if let Some(listening_socket) = table_lock.get_mut(&(UNDEFINED_ADDR, UNDEFINED_PORT)) {
-								if tcp_packet.get_destination() == listening_socket.src_port {
-									listening_socket
-								} else {
-									// send rst
-									warn!("port is not open: {}: {}->{}", src_addr, tcp_packet.get_source(), tcp_packet.get_destination());
-									continue;
-								}
-							} else {
-								// send rst
-									warn!("port is not open: {}: {}->{}", src_addr, tcp_packet.get_source(), tcp_packet.get_destination());
-								continue;
-							}
-						} else {
-							sock.unwrap()
-						}
-					};
-					debug!("incoming: {}:{}", src_addr, tcp_packet.get_source());
-					if !util::is_correct_checksum(&tcp_packet, &src_addr, &self.my_ip)
-						|| !util::is_valid_seq_num(socket, &tcp_packet)
-					{
-						continue;
+					// 1つのセグメントの処理に失敗しても受信は続ける
+					if let Err(e) = self.segment_arrives(&mut ts, &tcp_packet, src_addr) {
+						error!("recv: {}:{}: {}", src_addr, tcp_packet.get_source(), e);
 					}
-					util::print_info(&tcp_packet, &src_addr, socket.dst_port, socket.status);
-					match socket.status {
-						TcpStatus::SynSent => {
-							self.syn_send_state_handler(&tcp_packet, socket, &mut ts)?;
-						}
-						TcpStatus::Established => {
-							self.established_state_handler(&tcp_packet, socket, &mut ts)?;
-						}
-						TcpStatus::FinWait1 => {
-							self.finwait_state_handler(&tcp_packet, socket, &mut ts)?;
-						}
-						TcpStatus::FinWait2 => {
-							self.finwait_state_handler(&tcp_packet, socket, &mut ts)?;
-						}
-						TcpStatus::Listen => {
-							self.listen_state_handler(&tcp_packet, socket, &mut ts, src_addr)?;
-						}
-						TcpStatus::SynRecv => {
-							self.syn_recv_state_handler(&tcp_packet, socket, src_addr)?;
-						}
-						TcpStatus::LastAck => {
-							self.lastack_state_handler(&tcp_packet, socket)?;
-						}
-
-						_ => {
-							warn!("unimplemented state: {:?}", socket.status);
-						}
-					}
-					socket.recv_param.window = tcp_packet.get_window();
 				}
 				Err(_) => {
 					warn!("packet received error");
@@ -275,17 +508,214 @@ impl TCPManager {
 		}
 	}
 
+	// 受け取ったセグメントを接続の状態に応じて処理する
+	fn segment_arrives(&self, ts: &mut TransportSender, tcp_packet: &TcpPacket, src_addr: Ipv4Addr) -> Result<(), failure::Error> {
+		let mut table_lock = self.connections.write().unwrap();
+		// TIME_WAITの4-tupleを再利用する新しい接続要求ならリスニングソケットに渡す (RFC 1122 4.2.2.13)
+		let key = (src_addr, tcp_packet.get_source(), tcp_packet.get_destination());
+		let listening_key = (UNDEFINED_ADDR, UNDEFINED_PORT, tcp_packet.get_destination());
+		let reusable = match (table_lock.get(&key), table_lock.get(&listening_key)) {
+			(Some(sock), Some(listening_socket)) => {
+				sock.status == TcpStatus::TimeWait
+					&& listening_socket.status == TcpStatus::Listen
+					&& util::is_correct_checksum(tcp_packet, &src_addr, &self.my_ip)
+					&& util::is_reusable_time_wait(sock, tcp_packet)
+			}
+			_ => false,
+		};
+		if reusable {
+			debug!("reuse TIME_WAIT connection: {:?}", key);
+			let on_close = table_lock.remove(&key).and_then(|socket| socket.on_close);
+			// コールバックはロックを外して呼ぶ
+			if let Some(on_close) = on_close {
+				drop(table_lock);
+				on_close(None);
+				table_lock = self.connections.write().unwrap();
+			}
+		}
+		let is_listening_socket = !table_lock.contains_key(&key);
+		let socket = {
+			// recv SYN while listening
+			match table_lock.get_mut(&key) {
+				Some(sock) => sock,
+				None => {
+					if let Some(listening_socket) = table_lock.get_mut(&listening_key) {
+						listening_socket
+					} else {
+						warn!("port is not open: {}: {}->{}", src_addr, tcp_packet.get_source(), tcp_packet.get_destination());
+						// 自分が使っていないポートはカーネルなど他の持ち主の通信なので、RSTを返さない
+						let owned = table_lock.keys().any(|stream_id| stream_id.2 == tcp_packet.get_destination());
+						if owned {
+							self.reset(ts, src_addr, tcp_packet)?;
+						}
+						return Ok(());
+					}
+				}
+			}
+		};
+		debug!("incoming: {}:{}", src_addr, tcp_packet.get_source());
+		if !util::is_correct_checksum(tcp_packet, &src_addr, &self.my_ip) {
+			return Ok(());
+		}
+		if socket.status == TcpStatus::TimeWait {
+			self.timewait_state_handler(tcp_packet, socket, ts)?;
+			return Ok(());
+		}
+		if tcp_packet.get_flags() & TcpFlags::RST > 0 {
+			if let Some(error) = self.rst_handler(tcp_packet, socket, ts, is_listening_socket)? {
+				let on_close = self.abort(&mut table_lock, key, error);
+				drop(table_lock);
+				if let Some(on_close) = on_close {
+					on_close(Some(error));
+				}
+			}
+			return Ok(());
+		}
+		let options = TcpOptions::parse(tcp_packet.get_options_raw());
+		if !util::is_valid_timestamp(socket, tcp_packet, &options) {
+			debug!("PAWS rejected a segment: seq {}", tcp_packet.get_sequence());
+			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+			return Ok(());
+		}
+		if !util::is_valid_seq_num(socket, tcp_packet) {
+			// ウィンドウ外のセグメントには現在のACKを返す
+			if tcp_packet.get_flags() & TcpFlags::RST == 0 {
+				socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+			}
+			return Ok(());
+		}
+		socket.keepalive.on_receive(Instant::now());
+		util::print_info(tcp_packet, &src_addr, socket.dst_port, socket.status, socket.congestion.as_ref());
+		if tcp_packet.get_flags() & TcpFlags::SYN > 0 {
+			// SYNで相手のウィンドウを初期化する
+			socket.recv_param.window = tcp_packet.get_window() as u32;
+			socket.send_param.wl1 = SeqNum(tcp_packet.get_sequence());
+			socket.send_param.wl2 = SeqNum(tcp_packet.get_acknowledgement());
+		} else if tcp_packet.get_flags() & TcpFlags::ACK > 0 {
+			socket.update_window(
+				SeqNum(tcp_packet.get_sequence()),
+				SeqNum(tcp_packet.get_acknowledgement()),
+				tcp_packet.get_window(),
+			);
+		}
+		socket.update_timestamps(
+			SeqNum(tcp_packet.get_sequence()),
+			SeqNum(tcp_packet.get_acknowledgement()),
+			&options,
+		);
+		socket.update_scoreboard(&options.sack_blocks);
+		match socket.status {
+			TcpStatus::SynSent => {
+				self.syn_send_state_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::Established => {
+				self.established_state_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::FinWait1 => {
+				self.finwait_state_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::FinWait2 => {
+				self.finwait_state_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::Listen => {
+				self.listen_state_handler(tcp_packet, socket, ts, src_addr)?;
+			}
+			TcpStatus::SynRecv if is_listening_socket => {
+				self.syn_recv_state_handler(tcp_packet, socket, src_addr)?;
+			}
+			TcpStatus::SynRecv => {
+				self.simultaneous_open_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::Closing => {
+				self.closing_state_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::CloseWait => {
+				self.closewait_state_handler(tcp_packet, socket, ts)?;
+			}
+			TcpStatus::LastAck => {
+				self.lastack_state_handler(tcp_packet, socket, ts)?;
+			}
+
+			_ => {
+				warn!("unimplemented state: {:?}", socket.status);
+			}
+		}
+		Ok(())
+	}
+
+	// 接続を破棄すべきRSTなら理由を返す (RFC 793 3.4, RFC 5961 3.2)
+	pub fn rst_handler(
+		&self,
+		recv_packet: &TcpPacket,
+		socket: &mut Socket,
+		ts: &mut TransportSender,
+		is_listening_socket: bool,
+	) -> Result<Option<TcpError>, failure::Error> {
+		let seq = SeqNum(recv_packet.get_sequence());
+		match socket.status {
+			TcpStatus::Listen => Ok(None),
+			// 送ったSYNへのACKが付いていなければ偽物
+			TcpStatus::SynSent => {
+				let ack = SeqNum(recv_packet.get_acknowledgement());
+				if recv_packet.get_flags() & TcpFlags::ACK > 0 && ack == socket.send_param.next {
+					Ok(Some(TcpError::ConnectionRefused))
+				} else {
+					Ok(None)
+				}
+			}
+			// リスニングソケットはリッスン状態に戻る
+			TcpStatus::SynRecv if is_listening_socket => {
+				if seq == socket.recv_param.next {
+					debug!("connection request was reset");
+					*socket = Socket::initialize(self.my_ip, None, socket.src_port, None, TcpStatus::Listen, socket.congestion_algorithm, socket.send_param.recv_buffer_size, socket.send_param.my_mss);
+				}
+				Ok(None)
+			}
+			_ => {
+				if seq == socket.recv_param.next {
+					let error = match socket.status {
+						TcpStatus::SynRecv => TcpError::ConnectionRefused,
+						_ => TcpError::ConnectionReset,
+					};
+					return Ok(Some(error));
+				}
+				// ウィンドウ内でもRCV.NXTと一致しなければ偽造を疑い、チャレンジACKを返す
+				if util::is_valid_seq_num(socket, recv_packet) {
+					socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+				}
+				Ok(None)
+			}
+		}
+	}
+
+	// 相手が先に閉じた。アプリが閉じるまでデータを送り続けられる
+	pub fn closewait_state_handler(
+		&self,
+		recv_packet: &TcpPacket,
+		socket: &mut Socket,
+		ts: &mut TransportSender,
+	) -> Result<(), failure::Error> {
+		if recv_packet.get_flags() & TcpFlags::ACK > 0 {
+			self.process_ack(recv_packet, socket, ts)?;
+		}
+		socket.send_pending(ts)
+	}
+
+	// FINがACKされたらCLOSEDになり、タイマスレッドがテーブルから消す
 	pub fn lastack_state_handler(
 		&self,
 		recv_packet: &TcpPacket,
 		socket: &mut Socket,
+		ts: &mut TransportSender,
 	) -> Result<(), failure::Error> {
 		if recv_packet.get_flags() & TcpFlags::ACK > 0 {
-			socket.status = TcpStatus::Closed;
-			socket.recv_param.next = recv_packet.get_sequence();
-			socket.send_param.una = recv_packet.get_acknowledgement();
+			self.process_ack(recv_packet, socket, ts)?;
+			if socket.is_fin_acked() {
+				socket.status = TcpStatus::Closed;
+				return Ok(());
+			}
 		}
-		Ok(())
+		socket.send_pending(ts)
 	}
 
 	pub fn listen_state_handler(
@@ -296,12 +726,28 @@ impl TCPManager {
 		src_addr: Ipv4Addr,
 	) -> Result<(), failure::Error> {
 		let recv_tcp_flag = recv_packet.get_flags();
+		if recv_tcp_flag & TcpFlags::RST > 0 {
+			return Ok(());
+		}
+		// acceptを待っている接続以外へのACKは古い接続のもの
+		if recv_tcp_flag & TcpFlags::ACK > 0 {
+			let accepting = self.backlog.read().unwrap().iter().any(|sock| {
+				sock.dst_addr == Some(src_addr)
+					&& sock.dst_port == Some(recv_packet.get_source())
+					&& sock.src_port == recv_packet.get_destination()
+			});
+			if !accepting {
+				self.reset(ts, src_addr, recv_packet)?;
+			}
+			return Ok(());
+		}
 		if recv_tcp_flag & TcpFlags::SYN > 0 {
 			socket.status = TcpStatus::SynRecv;
 			socket.dst_port = Some(recv_packet.get_source());
 			socket.dst_addr = Some(src_addr);
-			socket.recv_param.irs = recv_packet.get_sequence() + 1;
-			socket.recv_param.next = recv_packet.get_sequence() + 1;
+			socket.recv_param.irs = SeqNum(recv_packet.get_sequence());
+			socket.recv_param.next = SeqNum(recv_packet.get_sequence()) + 1;
+			socket.negotiate_options(&TcpOptions::parse(recv_packet.get_options_raw()));
 			socket.send_tcp_packet(ts, TcpFlags::SYN | TcpFlags::ACK, None)?;
 		}
 		Ok(())
@@ -316,15 +762,15 @@ impl TCPManager {
 		let recv_tcp_flag = recv_packet.get_flags();
 		if recv_tcp_flag & TcpFlags::ACK > 0 {
 			// 接続済みソケットの生成
-			let mut new_socket = Socket::create_established(self.my_ip, src_addr, recv_packet.get_destination(), recv_packet.get_source(), &socket.send_param, &socket.recv_param);
-			new_socket.recv_param.next = recv_packet.get_sequence();
-			new_socket.send_param.una = recv_packet.get_acknowledgement();
+			let mut new_socket = Socket::create_established(self.my_ip, src_addr, recv_packet.get_destination(), recv_packet.get_source(), &socket.send_param, &socket.recv_param, socket.congestion_algorithm);
+			new_socket.recv_param.next = SeqNum(recv_packet.get_sequence());
+			new_socket.acknowledge(SeqNum(recv_packet.get_acknowledgement()));
 
 			let mut que_lock = self.backlog.write().unwrap();
 			que_lock.push_back(new_socket);
 
 			// リスニングソケットはリッスン状態に戻る
-			*socket = Socket::initialize(self.my_ip, Some(src_addr), recv_packet.get_destination(), Some(recv_packet.get_source()), TcpStatus::Listen);
+			*socket = Socket::initialize(self.my_ip, Some(src_addr), recv_packet.get_destination(), Some(recv_packet.get_source()), TcpStatus::Listen, socket.congestion_algorithm, socket.send_param.recv_buffer_size, socket.send_param.my_mss);
 		}
 		Ok(())
 	}
@@ -336,17 +782,59 @@ impl TCPManager {
 		ts: &mut TransportSender,
 	) -> Result<(), failure::Error> {
 		let recv_tcp_flag = recv_packet.get_flags();
-		if recv_tcp_flag & TcpFlags::SYN > 0 {
+		let ack = SeqNum(recv_packet.get_acknowledgement());
+		// 送ったSYNをACKしていないセグメントは古い接続のもの
+		if recv_tcp_flag & TcpFlags::ACK > 0 && ack != socket.send_param.next {
+			warn!("unacceptable ack in SYN-SENT: {}", ack);
+			if let Some(dst_addr) = socket.dst_addr {
+				self.reset(ts, dst_addr, recv_packet)?;
+			}
+			return Ok(());
+		}
+		if recv_tcp_flag & TcpFlags::SYN == 0 {
+			return Ok(());
+		}
+		socket.recv_param.irs = SeqNum(recv_packet.get_sequence());
+		socket.recv_param.next = SeqNum(recv_packet.get_sequence()) + 1;
+		socket.negotiate_options(&TcpOptions::parse(recv_packet.get_options_raw()));
+		if recv_tcp_flag & TcpFlags::ACK > 0 {
+			socket.acknowledge(ack);
+			debug!("connection established",);
+			socket.status = TcpStatus::Established;
+			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+		} else {
+			// 同時オープン: 送ったSYNにACKを付けて送り直す (RFC 793 3.4 Figure 8)
+			debug!("simultaneous open");
 			socket.status = TcpStatus::SynRecv;
-			if recv_tcp_flag & TcpFlags::ACK > 0 {
-				debug!("connection established",);
-				socket.status = TcpStatus::Established;
+			if let Some(entry) = socket.retransmission_queue.front_mut() {
+				entry.flag |= TcpFlags::ACK;
 			}
+			socket.retransmit(ts)?;
+		}
+		Ok(())
+	}
+
+	// 同時オープンでSYN-RECEIVEDになった能動側のソケット。SYNがACKされたら確立する
+	pub fn simultaneous_open_handler(
+		&self,
+		recv_packet: &TcpPacket,
+		socket: &mut Socket,
+		ts: &mut TransportSender,
+	) -> Result<(), failure::Error> {
+		let recv_tcp_flag = recv_packet.get_flags();
+		let ack = SeqNum(recv_packet.get_acknowledgement());
+		if recv_tcp_flag & TcpFlags::ACK == 0 || ack != socket.send_param.next {
+			return Ok(());
+		}
+		socket.acknowledge(ack);
+		debug!("connection established",);
+		socket.status = TcpStatus::Established;
+		// 確立させたACKに載っているデータやFINは確立後と同じように処理する
+		if recv_tcp_flag & TcpFlags::SYN == 0
+			&& (!recv_packet.payload().is_empty() || recv_tcp_flag & TcpFlags::FIN > 0)
+		{
+			self.established_state_handler(recv_packet, socket, ts)?;
 		}
-		socket.recv_param.irs = recv_packet.get_sequence();
-		socket.recv_param.next = recv_packet.get_sequence() + 1;
-		socket.send_param.una = recv_packet.get_acknowledgement();
-		socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
 		Ok(())
 	}
 
@@ -358,26 +846,55 @@ impl TCPManager {
 	) -> Result<(), failure::Error> {
 		let recv_tcp_flag = recv_packet.get_flags();
 		let payload = recv_packet.payload();
+		let seq = SeqNum(recv_packet.get_sequence());
 
+		self.process_ack(recv_packet, socket, ts)?;
 		if recv_tcp_flag & TcpFlags::FIN > 0 {
-			socket.recv_param.next = payload.len() as u32 + recv_packet.get_sequence() + 1;
-			socket.send_param.una = recv_packet.get_acknowledgement();
+			socket.reassembly.insert_fin(socket.recv_param.next, socket.send_param.window, seq + payload.len() as u32);
+		}
+		let mut ack_now = false;
+		if !payload.is_empty() {
+			debug!("recv payload len: {}, seq: {}", payload.len(), seq);
+			let in_order = seq == socket.recv_param.next && socket.reassembly.is_empty();
+			socket.receive_data(seq, payload);
+			ack_now = socket.delay_ack(in_order);
+		}
+
+		// FINは手前のデータが全て揃ってから処理する。穴が埋まったときは先に届いたFINを使う
+		if socket.reassembly.take_fin(socket.recv_param.next) {
+			// FINはアプリが閉じたときに送る
+			socket.recv_param.next += 1;
 			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
-			socket.send_tcp_packet(ts, TcpFlags::FIN | TcpFlags::ACK, None)?;
-			socket.status = TcpStatus::LastAck;
-			return Ok(());
+			socket.status = TcpStatus::CloseWait;
+			return socket.send_pending(ts);
 		}
 
-		debug!("recv payload len: {}, seq: {}", payload.len(), recv_packet.get_sequence());
-		socket.buffer.extend_from_slice(payload);
-		socket.recv_param.next = recv_packet.get_sequence() + payload.len() as u32;
-		socket.send_param.una = recv_packet.get_acknowledgement();
-		if payload.len() > 0 {
+		// ACKでウィンドウが進んだ分を送る。送れればACKはそれに載る
+		socket.send_pending(ts)?;
+		if ack_now && socket.has_pending_ack() {
 			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
 		}
 		Ok(())
 	}
 
+	// ACKで送信側を進める。重複ACKなら高速再送し、SACKでロスが分かれば再送する
+	fn process_ack(
+		&self,
+		recv_packet: &TcpPacket,
+		socket: &mut Socket,
+		ts: &mut TransportSender,
+	) -> Result<(), failure::Error> {
+		let ack = SeqNum(recv_packet.get_acknowledgement());
+		let payload_len = recv_packet.payload().len();
+		if socket.is_duplicate_ack(ack, payload_len, recv_packet.get_flags(), recv_packet.get_window()) {
+			socket.on_duplicate_ack(ts)?;
+		} else if socket.acknowledge(ack) {
+			socket.retransmit_on_partial_ack(ts)?;
+		}
+		socket.recv_param.last_window_field = recv_packet.get_window();
+		socket.retransmit_lost(ts)
+	}
+
 	pub fn finwait_state_handler(
 		&self,
 		recv_packet: &TcpPacket,
@@ -385,18 +902,68 @@ impl TCPManager {
 		ts: &mut TransportSender,
 	) -> Result<(), failure::Error> {
 		let recv_tcp_flag = recv_packet.get_flags();
+		let payload = recv_packet.payload();
+		let seq = SeqNum(recv_packet.get_sequence());
+		if recv_tcp_flag & TcpFlags::ACK > 0 {
+			self.process_ack(recv_packet, socket, ts)?;
+		}
+		let fin_acked = socket.is_fin_acked();
+		if socket.status == TcpStatus::FinWait1 && fin_acked {
+			socket.status = TcpStatus::FinWait2;
+		}
 		if recv_tcp_flag & TcpFlags::FIN > 0 {
-			socket.status = TcpStatus::Closing;
-			socket.recv_param.next = recv_packet.get_sequence() + 1;
-			socket.send_param.una = recv_packet.get_acknowledgement();
+			socket.reassembly.insert_fin(socket.recv_param.next, socket.send_param.window, seq + payload.len() as u32);
+		}
+		// 相手はFINを送るまでデータを送ってくる
+		if !payload.is_empty() {
+			socket.receive_data(seq, payload);
+		}
+		if socket.reassembly.take_fin(socket.recv_param.next) {
+			socket.recv_param.next += 1;
 			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
-			if recv_tcp_flag & TcpFlags::ACK > 0 {
-				socket.status = TcpStatus::TimeWait;
+			if fin_acked {
+				socket.enter_time_wait(self.time_wait_duration);
+			} else {
+				// 同時クローズ: こちらのFINへのACKを待つ
+				socket.status = TcpStatus::Closing;
 			}
-		} else if recv_tcp_flag & TcpFlags::ACK > 0 {
-			socket.status = TcpStatus::FinWait2;
-			socket.recv_param.next = recv_packet.get_sequence();
-			socket.send_param.una = recv_packet.get_acknowledgement();
+			return Ok(());
+		}
+		if !payload.is_empty() {
+			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+		}
+		// FINの前に残っているデータを送る
+		socket.send_pending(ts)
+	}
+
+	pub fn closing_state_handler(
+		&self,
+		recv_packet: &TcpPacket,
+		socket: &mut Socket,
+		ts: &mut TransportSender,
+	) -> Result<(), failure::Error> {
+		if recv_packet.get_flags() & TcpFlags::ACK > 0 {
+			self.process_ack(recv_packet, socket, ts)?;
+			if socket.is_fin_acked() {
+				socket.enter_time_wait(self.time_wait_duration);
+				return Ok(());
+			}
+		}
+		socket.send_pending(ts)
+	}
+
+	pub fn timewait_state_handler(
+		&self,
+		recv_packet: &TcpPacket,
+		socket: &mut Socket,
+		ts: &mut TransportSender,
+	) -> Result<(), failure::Error> {
+		// FINの再送はこちらのACKが失われたということなので、ACKし直して2MSLを数え直す
+		// RSTで早く閉じると古いセグメントを受け止められなくなるので無視する (RFC 1337)
+		let recv_tcp_flag = recv_packet.get_flags();
+		if recv_tcp_flag & TcpFlags::FIN > 0 && recv_tcp_flag & TcpFlags::RST == 0 {
+			socket.send_tcp_packet(ts, TcpFlags::ACK, None)?;
+			socket.enter_time_wait(self.time_wait_duration);
 		}
 		Ok(())
 	}
@@ -411,11 +978,16 @@ impl TCPManager {
 		loop {
 			let table_lock = self.connections.read().unwrap();
 			if let Some(socket) = table_lock.get(&stream_id) {
-				if socket.buffer.len() != 0 {
+				if !socket.buffer.is_empty() {
 					break;
 				}
+				// 相手のFINより前のデータを全て読んだ
+				if socket.read_closed || socket.is_peer_closed() {
+					return Ok(0);
+				}
 			} else {
-				return Ok(0);
+				// RSTやタイムアウトで切れたか、存在しない接続
+				return Err(self.stream_error(&stream_id));
 			}
 			drop(table_lock);
 			thread::sleep(Duration::from_millis(WAIT_MS));
@@ -430,9 +1002,16 @@ impl TCPManager {
 				buffer[..actual_read_size].copy_from_slice(&socket.buffer[..actual_read_size]);
 				socket.buffer = socket.buffer[actual_read_size..].to_vec();
 				debug!("sock buf: {}", socket.buffer.len());
+				// 空いた分を相手に知らせる
+				let can_receive = matches!(socket.status, TcpStatus::Established | TcpStatus::FinWait1 | TcpStatus::FinWait2);
+				// ソケットを開くのはウィンドウ更新を送るときだけ
+				if socket.update_recv_window() && can_receive {
+					let (mut ts, _) = util::create_tcp_channel()?;
+					socket.send_tcp_packet(&mut ts, TcpFlags::ACK, None)?;
+				}
 				Ok(actual_read_size)
 			}
-			None => Err(failure::err_msg("stream was not found.")),
+			None => Err(self.stream_error(&stream_id)),
 		}
 	}
 }
@@ -0,0 +1,266 @@
+use std::cmp::{max, min};
+use std::time::{Duration, Instant};
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(10);
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(2 * 60 * 60); // RFC 1122 4.2.3.6
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+const KEEPALIVE_COUNT: u32 = 9;
+
+// RFC 6298 の再送タイマ
+pub struct RetransmissionTimer {
+	pub srtt: Option<Duration>,
+	pub rttvar: Duration,
+	pub rto: Duration,
+	pub expires_at: Option<Instant>,
+}
+
+impl RetransmissionTimer {
+	pub fn new() -> Self {
+		RetransmissionTimer {
+			srtt: None,
+			rttvar: Duration::from_secs(0),
+			rto: INITIAL_RTO,
+			expires_at: None,
+		}
+	}
+
+	pub fn is_running(&self) -> bool {
+		self.expires_at.is_some()
+	}
+
+	pub fn is_expired(&self, now: Instant) -> bool {
+		match self.expires_at {
+			Some(expires_at) => now >= expires_at,
+			None => false,
+		}
+	}
+
+	// 動いていなければタイマを起動する (RFC 6298 5.1)
+	pub fn start(&mut self, now: Instant) {
+		if self.expires_at.is_none() {
+			self.restart(now);
+		}
+	}
+
+	pub fn restart(&mut self, now: Instant) {
+		self.expires_at = Some(now + self.rto);
+	}
+
+	pub fn stop(&mut self) {
+		self.expires_at = None;
+	}
+
+	// タイムアウト時にRTOを倍にする (RFC 6298 5.5)
+	pub fn backoff(&mut self) {
+		self.rto = min(self.rto * 2, MAX_RTO);
+	}
+
+	// 再送していないセグメントから得たRTTでSRTT/RTTVARを更新する (RFC 6298 2.2, 2.3)
+	pub fn on_rtt_sample(&mut self, rtt: Duration) {
+		match self.srtt {
+			None => {
+				self.srtt = Some(rtt);
+				self.rttvar = rtt / 2;
+			}
+			Some(srtt) => {
+				let delta = srtt.abs_diff(rtt);
+				self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+				self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+			}
+		}
+		let rto = self.srtt.unwrap() + max(CLOCK_GRANULARITY, self.rttvar * 4);
+		self.rto = min(max(rto, MIN_RTO), MAX_RTO);
+		debug!("rtt: {:?}, srtt: {:?}, rttvar: {:?}, rto: {:?}", rtt, self.srtt.unwrap(), self.rttvar, self.rto);
+	}
+}
+
+impl Default for RetransmissionTimer {
+	fn default() -> Self {
+		RetransmissionTimer::new()
+	}
+}
+
+// 相手のウィンドウが0の間プローブを送るパーシストタイマ (RFC 1122 4.2.2.17)
+pub struct PersistTimer {
+	pub expires_at: Option<Instant>,
+	backoff: u32,
+}
+
+impl PersistTimer {
+	pub fn new() -> Self {
+		PersistTimer {
+			expires_at: None,
+			backoff: 0,
+		}
+	}
+
+	pub fn is_running(&self) -> bool {
+		self.expires_at.is_some()
+	}
+
+	pub fn is_expired(&self, now: Instant) -> bool {
+		match self.expires_at {
+			Some(expires_at) => now >= expires_at,
+			None => false,
+		}
+	}
+
+	// 動いていなければRTOを最初の間隔として起動する
+	pub fn start(&mut self, now: Instant, rto: Duration) {
+		if self.expires_at.is_none() {
+			self.backoff = 0;
+			self.expires_at = Some(now + rto);
+		}
+	}
+
+	// プローブを送るたびに間隔を倍にする。ウィンドウが開くまで諦めない
+	pub fn backoff(&mut self, now: Instant, rto: Duration) {
+		self.backoff = min(self.backoff + 1, 16);
+		let interval = rto.checked_mul(1 << self.backoff).map_or(MAX_RTO, |interval| min(interval, MAX_RTO));
+		self.expires_at = Some(now + interval);
+	}
+
+	pub fn stop(&mut self) {
+		self.expires_at = None;
+		self.backoff = 0;
+	}
+}
+
+impl Default for PersistTimer {
+	fn default() -> Self {
+		PersistTimer::new()
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct KeepaliveConfig {
+	pub idle: Duration,     //最初のプローブを送るまでの無通信時間
+	pub interval: Duration, //応答が無いときのプローブ間隔
+	pub count: u32,         //接続を切るまでに送るプローブの数
+}
+
+impl Default for KeepaliveConfig {
+	fn default() -> Self {
+		KeepaliveConfig {
+			idle: KEEPALIVE_IDLE,
+			interval: KEEPALIVE_INTERVAL,
+			count: KEEPALIVE_COUNT,
+		}
+	}
+}
+
+// 無通信の接続で相手が生きているか確かめるキープアライブ (RFC 1122 4.2.3.6)
+pub struct KeepaliveTimer {
+	pub config: Option<KeepaliveConfig>,
+	last_received: Instant,
+	probes_sent: u32,
+}
+
+impl KeepaliveTimer {
+	pub fn new() -> Self {
+		KeepaliveTimer {
+			config: None,
+			last_received: Instant::now(),
+			probes_sent: 0,
+		}
+	}
+
+	pub fn set_config(&mut self, config: Option<KeepaliveConfig>, now: Instant) {
+		self.config = config;
+		self.on_receive(now);
+	}
+
+	// 相手から何か届けば生きている
+	pub fn on_receive(&mut self, now: Instant) {
+		self.last_received = now;
+		self.probes_sent = 0;
+	}
+
+	pub fn is_expired(&self, now: Instant) -> bool {
+		match self.config {
+			Some(config) => now >= self.last_received + config.idle + config.interval * self.probes_sent,
+			None => false,
+		}
+	}
+
+	// 決められた数のプローブに応答が無かった
+	pub fn is_exhausted(&self) -> bool {
+		self.config.is_some_and(|config| self.probes_sent >= config.count)
+	}
+
+	pub fn on_probe_sent(&mut self) {
+		self.probes_sent += 1;
+	}
+}
+
+impl Default for KeepaliveTimer {
+	fn default() -> Self {
+		KeepaliveTimer::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn first_rtt_sample() {
+		let mut timer = RetransmissionTimer::new();
+		timer.on_rtt_sample(Duration::from_millis(800));
+		assert_eq!(timer.srtt, Some(Duration::from_millis(800)));
+		assert_eq!(timer.rttvar, Duration::from_millis(400));
+		assert_eq!(timer.rto, Duration::from_millis(2400));
+	}
+
+	#[test]
+	fn later_rtt_sample() {
+		let mut timer = RetransmissionTimer::new();
+		timer.on_rtt_sample(Duration::from_millis(800));
+		timer.on_rtt_sample(Duration::from_millis(400));
+		assert_eq!(timer.srtt, Some(Duration::from_millis(750)));
+		assert_eq!(timer.rttvar, Duration::from_millis(400));
+		assert_eq!(timer.rto, Duration::from_millis(2350));
+	}
+
+	#[test]
+	fn rto_has_minimum() {
+		let mut timer = RetransmissionTimer::new();
+		timer.on_rtt_sample(Duration::from_millis(10));
+		assert_eq!(timer.rto, MIN_RTO);
+	}
+
+	#[test]
+	fn rto_has_maximum() {
+		let mut timer = RetransmissionTimer::new();
+		timer.on_rtt_sample(Duration::from_secs(30));
+		assert_eq!(timer.rto, MAX_RTO);
+	}
+
+	#[test]
+	fn backoff_doubles_up_to_maximum() {
+		let mut timer = RetransmissionTimer::new();
+		timer.backoff();
+		assert_eq!(timer.rto, INITIAL_RTO * 2);
+		timer.backoff();
+		assert_eq!(timer.rto, INITIAL_RTO * 4);
+		for _ in 0..10 {
+			timer.backoff();
+		}
+		assert_eq!(timer.rto, MAX_RTO);
+	}
+
+	#[test]
+	fn start_keeps_running_timer() {
+		let mut timer = RetransmissionTimer::new();
+		let now = Instant::now();
+		timer.start(now);
+		timer.start(now + Duration::from_millis(500));
+		assert!(!timer.is_expired(now + INITIAL_RTO - Duration::from_millis(1)));
+		assert!(timer.is_expired(now + INITIAL_RTO));
+		timer.stop();
+		assert!(!timer.is_running());
+	}
+}
@@ -1,12 +1,19 @@
-use super::socket::{Socket, TcpStatus};
+use super::congestion::CongestionControl;
+use super::option::TcpOptions;
+use super::seq::SeqNum;
+use super::socket::{Socket, TcpStatus, TCP_SIZE};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::tcp::{self, TcpFlags, TcpPacket};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::Packet;
 use pnet::transport::{
 	self, TransportChannelType, TransportProtocol, TransportReceiver, TransportSender,
 };
 use std::collections::HashMap;
 use std::fs;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
 
 pub fn load_env() -> HashMap<String, String> {
 	let contents = fs::read_to_string(".env").expect("Failed to read env file");
@@ -37,7 +44,7 @@ pub fn is_correct_checksum(tcp_packet: &TcpPacket, src_addr: &Ipv4Addr, my_ip: &
 	}
 }
 
-pub fn print_info(packet: &TcpPacket, src_addr: &Ipv4Addr, sock_port: Option<u16>, status: TcpStatus) {
+pub fn print_info(packet: &TcpPacket, src_addr: &Ipv4Addr, sock_port: Option<u16>, status: TcpStatus, congestion: &dyn CongestionControl) {
 	debug!("=================================");
 	debug!("From Addr: {}", src_addr);
 	debug!("From Port: {}", packet.get_source());
@@ -49,6 +56,7 @@ pub fn print_info(packet: &TcpPacket, src_addr: &Ipv4Addr, sock_port: Option<u16
 	} else {
 		debug!("scport   : NONE");
 	}
+	debug!("cc       : {} ({})", congestion.name(), congestion.state());
 }
 
 pub fn flag_to_string(flag: u16) -> String {
@@ -83,6 +91,123 @@ pub fn flag_to_string(flag: u16) -> String {
 	flag_str
 }
 
+// 受信ウィンドウとセグメントが重なっているか (RFC 793 3.3)
 pub fn is_valid_seq_num(socket: &Socket, recv_packet: &TcpPacket) -> bool {
-	return socket.recv_param.next == 0 || socket.recv_param.next == recv_packet.get_sequence();
+	// 同期前はRCV.NXTが決まっていない
+	if socket.status == TcpStatus::Listen || socket.status == TcpStatus::SynSent {
+		return true;
+	}
+	let rcv_nxt = socket.recv_param.next;
+	let rcv_end = rcv_nxt + socket.send_param.window;
+	let seq = SeqNum(recv_packet.get_sequence());
+	// 同時オープンでは、相手のSYN-ACKが受け取ったSYNと同じシーケンス番号で届く
+	if socket.status == TcpStatus::SynRecv
+		&& recv_packet.get_flags() & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK
+		&& seq == socket.recv_param.irs
+	{
+		return true;
+	}
+	let mut seg_len = recv_packet.payload().len() as u32;
+	if recv_packet.get_flags() & (TcpFlags::SYN | TcpFlags::FIN) > 0 {
+		seg_len += 1;
+	}
+	match (seg_len, socket.send_param.window) {
+		(0, 0) => seq == rcv_nxt,
+		(0, _) => seq.is_between(rcv_nxt, rcv_end),
+		(_, 0) => false,
+		(_, _) => seq.is_between(rcv_nxt, rcv_end) || (seq + (seg_len - 1)).is_between(rcv_nxt, rcv_end),
+	}
+}
+
+// PAWS: TS.Recentより古いTSvalのセグメントを捨てる (RFC 7323 5.3)
+pub fn is_valid_timestamp(socket: &Socket, recv_packet: &TcpPacket, options: &TcpOptions) -> bool {
+	if recv_packet.get_flags() & (TcpFlags::SYN | TcpFlags::RST) > 0 {
+		return true;
+	}
+	match (socket.recv_param.ts_recent, options.timestamps) {
+		(Some(ts_recent), Some((tsval, _))) => {
+			// 24日以上更新されていないTS.Recentは無効とみなす
+			tsval.wrapping_sub(ts_recent) as i32 >= 0 || socket.recv_param.ts_recent_at.elapsed() > PAWS_IDLE_LIMIT
+		}
+		_ => true,
+	}
+}
+
+// TIME_WAITの接続と同じ4-tupleで来たSYNが、古い接続のセグメントと紛れないか (RFC 6191)
+pub fn is_reusable_time_wait(socket: &Socket, recv_packet: &TcpPacket) -> bool {
+	if recv_packet.get_flags() & (TcpFlags::SYN | TcpFlags::ACK) != TcpFlags::SYN {
+		return false;
+	}
+	let options = TcpOptions::parse(recv_packet.get_options_raw());
+	match (socket.recv_param.ts_recent, options.timestamps) {
+		(Some(ts_recent), Some((tsval, _))) => tsval.wrapping_sub(ts_recent) as i32 > 0,
+		_ => SeqNum(recv_packet.get_sequence()) > socket.recv_param.next,
+	}
+}
+
+// 対応する接続の無いセグメントにRSTを返す (RFC 793 3.4 Reset Generation)
+pub fn send_reset(
+	ts: &mut TransportSender,
+	my_ip: &Ipv4Addr,
+	dst_addr: &Ipv4Addr,
+	recv_packet: &TcpPacket,
+) -> Result<(), failure::Error> {
+	let recv_tcp_flag = recv_packet.get_flags();
+	// RSTにはRSTを返さない
+	if recv_tcp_flag & TcpFlags::RST > 0 {
+		return Ok(());
+	}
+	let mut tcp_buffer = vec![0u8; TCP_SIZE];
+	let mut tcp_packet = MutableTcpPacket::new(&mut tcp_buffer).unwrap();
+	tcp_packet.set_source(recv_packet.get_destination());
+	tcp_packet.set_destination(recv_packet.get_source());
+	tcp_packet.set_data_offset((TCP_SIZE / 4) as u8);
+	if recv_tcp_flag & TcpFlags::ACK > 0 {
+		tcp_packet.set_sequence(recv_packet.get_acknowledgement());
+		tcp_packet.set_flags(TcpFlags::RST);
+	} else {
+		let mut seg_len = recv_packet.payload().len() as u32;
+		if recv_tcp_flag & TcpFlags::SYN > 0 {
+			seg_len += 1;
+		}
+		if recv_tcp_flag & TcpFlags::FIN > 0 {
+			seg_len += 1;
+		}
+		tcp_packet.set_sequence(0);
+		tcp_packet.set_acknowledgement((SeqNum(recv_packet.get_sequence()) + seg_len).0);
+		tcp_packet.set_flags(TcpFlags::RST | TcpFlags::ACK);
+	}
+	tcp_packet.set_checksum(tcp::ipv4_checksum(&tcp_packet.to_immutable(), my_ip, dst_addr));
+	ts.send_to(tcp_packet, IpAddr::V4(*dst_addr))?;
+	debug!("sent rst: {}:{}", dst_addr, recv_packet.get_source());
+	Ok(())
+}
+
+// 1秒あたりの送信数を制限する
+pub struct RateLimiter {
+	limit: u32,
+	window_start: Instant,
+	count: u32,
+}
+
+impl RateLimiter {
+	pub fn new(limit: u32) -> Self {
+		RateLimiter {
+			limit,
+			window_start: Instant::now(),
+			count: 0,
+		}
+	}
+
+	pub fn allow(&mut self, now: Instant) -> bool {
+		if now - self.window_start >= Duration::from_secs(1) {
+			self.window_start = now;
+			self.count = 0;
+		}
+		if self.count >= self.limit {
+			return false;
+		}
+		self.count += 1;
+		true
+	}
 }